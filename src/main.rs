@@ -1,8 +1,13 @@
 mod modules;
 
 fn main() {
-    match modules::cli::read_command() {
-        Ok(mut app) => app.run(), 
-        Err(err) => eprintln!("{}", err),
+    let code = match modules::cli::read_command() {
+        Ok(mut app) => app.run(),
+        Err(modules::cli::CliError::NoneError) => 0,
+        Err(err) => {
+            eprintln!("{}", err);
+            1
+        }
     };
+    std::process::exit(code);
 }
@@ -1,70 +1,237 @@
 use super::config;
+use super::http::{self, HttpRequest, Route};
 use super::nvidia;
-use super::util::NaiveDateTimeWrapper;
+use super::protocol::{
+    self, AdminCommand, Booking, Envelope, ErrorCode, Event, EventKind, Query, Reply, Request,
+};
+use super::recurrence::Recurrence;
+use super::util::{NaiveDateTimeWrapper, Util};
 use chrono::{prelude::*, Duration};
 use lettre::transport::smtp::authentication::Credentials;
 use lettre::{Message, SmtpTransport, Transport};
+use native_tls::{Certificate, Identity, TlsAcceptor, TlsConnector, TlsStream};
 use serde::{Deserialize, Serialize};
-use std::cmp::*;
+use std::cmp::{Ordering, Reverse};
+use std::collections::VecDeque;
 use std::io::{prelude::*, BufReader, Write};
 use std::net::{TcpListener, TcpStream};
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, OpenOptionsExt, PermissionsExt};
+use std::os::unix::io::AsRawFd;
+use std::os::unix::net::{UnixListener, UnixStream};
 use std::str;
-use std::sync::Mutex;
+use std::sync::{mpsc, Arc, Mutex};
 use std::thread;
 use std::time;
 
 use lazy_static::lazy_static;
 
+mod simulate;
+pub use simulate::Simulator;
+
 #[derive(Clone, Debug)] //记录每个user的申请时刻，作为排序依据
 pub struct User {
     urg: bool,
-    finish: bool,
     timestamp: i64,
     email: String,
     date_time: NaiveDateTime,
-    // time: Option<NaiveTime>,
+    duration: Option<i64>, //预计使用时长(分钟)
+    slot: bool,            //时段预约，仅在[开始, 开始+时长)内使用
+    early: bool,           //允许在开始时刻前的提前窗口内分配
+    deadline: Option<NaiveDateTime>,
 }
 
 impl User {
-    pub fn new(
-        email: String,
-        date: Option<NaiveDate>,
-        time: Option<NaiveTime>,
-        urg: bool,
-        finish: bool,
-    ) -> User {
+    pub fn new(email: String, date: Option<NaiveDate>, time: Option<NaiveTime>, urg: bool) -> User {
         //不指定时刻默认采用当前时刻
-        if date.is_none() || time.is_none() {
-            User {
-                urg,
-                finish,
-                timestamp: Local::now().timestamp(),
-                email,
-                date_time: NaiveDateTimeWrapper::from(Local::now()).into(),
+        let date_time = match (date, time) {
+            (Some(date), Some(time)) => NaiveDateTime::new(date, time),
+            _ => NaiveDateTimeWrapper::from(Local::now()).into(),
+        };
+        User {
+            urg,
+            timestamp: Local::now().timestamp(),
+            email,
+            date_time,
+            duration: None,
+            slot: false,
+            early: false,
+            deadline: None,
+        }
+    }
+    pub fn with_duration(mut self, duration: Option<i64>) -> User {
+        self.duration = duration;
+        self
+    }
+    pub fn with_slot(mut self, slot: bool) -> User {
+        self.slot = slot;
+        self
+    }
+    pub fn with_early(mut self, early: bool) -> User {
+        self.early = early;
+        self
+    }
+    pub fn with_deadline(mut self, deadline: Option<NaiveDateTime>) -> User {
+        self.deadline = deadline;
+        self
+    }
+}
+impl From<User> for Booking {
+    fn from(user: User) -> Booking {
+        Booking {
+            email: user.email,
+            date_time: user.date_time.to_string(),
+            duration: user.duration,
+            slot: user.slot,
+            early: user.early,
+            deadline: user.deadline.map(|deadline| deadline.to_string()),
+        }
+    }
+}
+
+//客户端，发送一条请求
+#[derive(Debug)]
+pub struct Client {
+    request: Request,
+}
+impl Client {
+    pub fn new(request: Request) -> Client {
+        Client { request }
+    }
+    //设置RUSTTIP_HOST时通过TLS连接远程服务端，否则优先使用本地套接字，不存在时退回TCP
+    //TCP连接无法确认对端用户，需附带API令牌
+    fn send(&self) -> Reply {
+        //订阅事件时持续等待，不设超时
+        let timeout = match self.request {
+            Request::Watch { .. } => None,
+            _ => Some(time::Duration::from_secs(config::REPLY_TIMEOUT_SECONDS)),
+        };
+        if let Ok(host) = std::env::var("RUSTTIP_HOST") {
+            return self.send_remote(&host, timeout);
+        }
+        let path = std::env::var("RUSTTIP_SOCKET").unwrap_or(config::SOCKET_PATH.to_string());
+        if let Ok(stream) = UnixStream::connect(&path) {
+            stream.set_read_timeout(timeout).ok();
+            return self.handshake(stream, None);
+        }
+        match TcpStream::connect(config::TCP_ADDR) {
+            Ok(stream) => {
+                stream.set_read_timeout(timeout).ok();
+                self.handshake(stream, Client::token())
             }
-        } else {
-            User {
-                urg,
-                finish,
-                timestamp: Local::now().timestamp(),
-                email,
-                date_time: NaiveDateTime::new(date.unwrap(), time.unwrap()),
+            Err(_) => Reply::error(
+                ErrorCode::Unavailable,
+                String::from("无法连接服务端，请确认服务已启动！"),
+            ),
+        }
+    }
+    //RUSTTIP_CA可指定额外信任的CA证书，用于自签名证书
+    fn send_remote(&self, host: &str, timeout: Option<time::Duration>) -> Reply {
+        let unavailable = |message: String| Reply::error(ErrorCode::Unavailable, message);
+        let mut builder = TlsConnector::builder();
+        if let Ok(path) = std::env::var("RUSTTIP_CA") {
+            let cert = std::fs::read(&path)
+                .ok()
+                .and_then(|pem| Certificate::from_pem(&pem).ok());
+            match cert {
+                Some(cert) => builder.add_root_certificate(cert),
+                None => return unavailable(format!("无法读取CA证书{}！", path)),
+            };
+        }
+        let connector = match builder.build() {
+            Ok(connector) => connector,
+            Err(err) => return unavailable(format!("TLS初始化失败: {}", err)),
+        };
+        let stream = match TcpStream::connect(host) {
+            Ok(stream) => stream,
+            Err(_) => return unavailable(format!("无法连接服务端{}，请确认服务已启动！", host)),
+        };
+        stream.set_read_timeout(timeout).ok();
+        let domain = host.rsplit_once(':').map_or(host, |(domain, _)| domain);
+        match connector.connect(domain, stream) {
+            Ok(stream) => self.handshake(stream, Client::token()),
+            Err(err) => unavailable(format!("TLS握手失败: {}", err)),
+        }
+    }
+    //远程连接的API令牌，取自RUSTTIP_TOKEN或主目录下的令牌文件
+    fn token() -> Option<String> {
+        if let Ok(token) = std::env::var("RUSTTIP_TOKEN") {
+            return Some(token);
+        }
+        let home = std::env::var("HOME").ok()?;
+        let path = std::path::Path::new(&home).join(config::CLIENT_TOKEN_FILE);
+        let token = std::fs::read_to_string(path).ok()?;
+        Some(token.trim().to_string())
+    }
+    //先交换协议版本，一致后再发送请求
+    fn handshake<S: Read + Write>(&self, mut stream: S, token: Option<String>) -> Reply {
+        let hello = Client::exchange(&mut stream, &Envelope::new(Request::Hello));
+        if !hello.ok {
+            return hello;
+        }
+        let envelope = Envelope::new(self.request.clone()).with_token(token);
+        match self.request {
+            Request::Watch { .. } => Client::watch(stream, &envelope),
+            _ => Client::exchange(&mut stream, &envelope),
+        }
+    }
+    //订阅成功后逐行打印事件，直到服务端断开
+    fn watch<S: Read + Write>(mut stream: S, envelope: &Envelope) -> Reply {
+        let mut line = serde_json::to_string(envelope).unwrap();
+        line.push('\n');
+        if stream.write_all(line.as_bytes()).is_err() {
+            return Reply::error(ErrorCode::Unavailable, String::from("请求发送失败！"));
+        }
+        let mut reader = BufReader::new(stream);
+        let mut line = String::new();
+        match reader.read_line(&mut line) {
+            Ok(n) if n > 0 => match serde_json::from_str::<Reply>(&line) {
+                Ok(reply) if reply.ok => println!("{}", reply),
+                Ok(reply) => return reply,
+                Err(_) => {
+                    return Reply::error(
+                        ErrorCode::Unavailable,
+                        String::from("无法解析服务端应答！"),
+                    )
+                }
+            },
+            _ => return Reply::error(ErrorCode::Unavailable, String::from("服务端无应答！")),
+        }
+        loop {
+            line.clear();
+            match reader.read_line(&mut line) {
+                Ok(n) if n > 0 => {
+                    if let Ok(event) = serde_json::from_str::<Event>(&line) {
+                        println!("{}", event);
+                    }
+                }
+                _ => return Reply::error(ErrorCode::Unavailable, String::from("服务端已断开！")),
             }
         }
     }
-    //向文件写
-    fn send_by_tcp(&self) {
-        let curr_info = UserWrapper::from(self.clone());
-        let mut stream = TcpStream::connect("127.0.0.1:7630").expect("Tcp connect failed");
-        let mut info = serde_json::to_string(&curr_info).unwrap();
-        info.push_str("\n");
-        stream
-            .write(info.as_bytes())
-            .expect("Failed to write to stream");
+    fn exchange<S: Read + Write>(stream: &mut S, envelope: &Envelope) -> Reply {
+        let unavailable = |message: &str| Reply::error(ErrorCode::Unavailable, message.to_string());
+        let mut line = serde_json::to_string(envelope).unwrap();
+        line.push('\n');
+        if stream.write_all(line.as_bytes()).is_err() {
+            return unavailable("请求发送失败！");
+        }
+        let mut line = String::new();
+        match BufReader::new(stream).read_line(&mut line) {
+            Ok(n) if n > 0 => {
+                serde_json::from_str(&line).unwrap_or_else(|_| unavailable("无法解析服务端应答！"))
+            }
+            _ => unavailable("服务端无应答！"),
+        }
     }
-    //user只修改条目，不删除
-    pub fn run(&mut self) {
-        self.send_by_tcp();
+    //打印应答，返回进程退出码
+    pub fn run(&self) -> i32 {
+        let reply = self.send();
+        if reply.ok {
+            println!("{}", reply);
+        } else {
+            eprintln!("{}", reply);
+        }
+        reply.exit_code()
     }
 }
 
@@ -74,8 +241,13 @@ pub struct Server {
     password: String,
 }
 lazy_static! {
-    static ref RECV_DATA: Mutex<Vec<UserWrapper>> = Mutex::new(Vec::new());
+    //请求信封、请求者uid及其连接，主循环处理后在原连接上应答
+    static ref RECV_DATA: Mutex<Vec<(Envelope, Option<u32>, Connection)>> = Mutex::new(Vec::new());
     static ref THREAD_ALIVE: Mutex<bool> = Mutex::new(true);
+    //正在读取请求的连接数，本机、HTTP与远程连接分别计数，互不挤占
+    static ref CONNECTIONS: Mutex<usize> = Mutex::new(0);
+    static ref HTTP_CONNECTIONS: Mutex<usize> = Mutex::new(0);
+    static ref REMOTE_CONNECTIONS: Mutex<usize> = Mutex::new(0);
 }
 impl Server {
     pub fn new(account: String, password: String) -> Server {
@@ -85,109 +257,604 @@ impl Server {
         TcpStream::connect(config::TCP_ADDR).is_ok()
     }
     //从监听中抓取一个数据，监听持续运行
-    fn receive_by_tcp(&self) -> Option<Vec<UserWrapper>> {
+    fn receive_by_tcp(&self) -> Option<Vec<(Envelope, Option<u32>, Connection)>> {
         let mut lck = RECV_DATA.lock().unwrap();
-        let ret = Some(lck.clone());
-        lck.clear();
-        ret
-    }
-    fn server_stop(&self,user:UserWrapper) ->bool {
-        if user.email==String::from("stop@stop.stop"){
-            let mut lck=THREAD_ALIVE.lock().unwrap();
-            *lck=false;
-            return true;
+        Some(std::mem::take(&mut *lck))
+    }
+    fn server_stop(&self) {
+        let mut lck = THREAD_ALIVE.lock().unwrap();
+        *lck = false;
+    }
+    //推送事件，只投递给各订阅者的写线程，不在主循环中等待写入；写线程已结束的订阅者移除
+    fn broadcast(&self, subscribers: &mut Vec<Subscriber>, events: Vec<Event>) {
+        subscribers.retain(|subscriber| !subscriber.writer.is_finished());
+        for event in events {
+            let mut line = serde_json::to_string(&event).unwrap();
+            line.push('\n');
+            for subscriber in subscribers.iter_mut() {
+                if event.matches(subscriber.filter.as_deref()) {
+                    subscriber.send(line.clone());
+                }
+            }
         }
-        false
     }
-    pub fn run(&self) {
+    //返回进程退出码
+    pub fn run(&self) -> i32 {
         if self.is_server_existed() {
-            return;
-        } else {
-            App::tcp_runtime();
+            return 0;
         }
-        let mut gpu = nvidia::Nvidia::new();
+        let settings = match config::Settings::load() {
+            Ok(settings) => settings,
+            Err(err) => {
+                eprintln!("{}", err);
+                return 1;
+            }
+        };
+        let mut gpus = nvidia::Nvidia::scan();
         let mut app_info = AppInfo::load();
         app_info.server_info = self.clone();
+        app_info.settings = settings;
+        let runtimes = App::tcp_runtime()
+            .and_then(|_| App::unix_runtime(&app_info.settings))
+            .and_then(|_| App::http_runtime(&app_info.settings))
+            .and_then(|_| App::remote_runtime(&app_info.settings));
+        if let Err(err) = runtimes {
+            eprintln!("{}", err);
+            return 1;
+        }
+        let mut subscribers: Vec<Subscriber> = Vec::new();
         'first_loop: loop {
+            let now: NaiveDateTime = NaiveDateTimeWrapper::from(Local::now()).into();
+            let holders = app_info.curr_users.clone();
             let users = self.receive_by_tcp();
             if let Some(users) = users {
-                for user in users {
-                    if self.server_stop(user.clone()){
-                        break 'first_loop;
+                for (envelope, uid, mut conn) in users {
+                    let request = envelope.request;
+                    if let Err(reply) = app_info.check_identity(
+                        &request,
+                        uid,
+                        conn.is_remote(),
+                        envelope.token.as_deref(),
+                    ) {
+                        App::reply(&mut conn, &reply);
+                        continue;
                     }
-                    //更新数据库
-                    if app_info.user_info.contains_key(&user.email) {
-                        if let Some(x) = app_info.user_info.get_mut(&user.email) {
-                            *x = user.clone();
+                    if let Request::Watch { email } = request {
+                        subscribers.retain(|subscriber| !subscriber.writer.is_finished());
+                        if subscribers.len() >= config::MAX_CONNECTIONS {
+                            App::reply(&mut conn, &App::busy());
+                            continue;
                         }
-                    } else {
-                        app_info.user_info.insert(user.email.clone(), user.clone());
+                        let body = match &email {
+                            Some(email) => format!("开始订阅用户{}的预约事件", email),
+                            None => String::from("开始订阅全部事件"),
+                        };
+                        App::reply(&mut conn, &Reply::ok(body));
+                        conn.set_write_timeout(time::Duration::from_secs(
+                            config::REPLY_TIMEOUT_SECONDS,
+                        ));
+                        subscribers.push(Subscriber::spawn(email, conn));
+                        continue;
                     }
-                    //邮件通知
-                    if user.finish {
-                        app_info.send_email(
-                            user.email.clone(),
-                            "任务注销通知",
-                            &format! {"用户{}注销成功！欢迎下次预约！",user.email},
-                        );
-                    } else {
-                        app_info.send_email(
-                            user.email.clone(),
-                            "服务器预约通知",
-                            &format! {"用户{}预约成功！服务器就绪后将自动通知您！",user.email},
-                        );
+                    let stop = matches!(
+                        request,
+                        Request::Admin {
+                            command: AdminCommand::Stop,
+                            ..
+                        }
+                    );
+                    let reply = app_info.handle(request, uid, gpus.len(), now);
+                    App::reply(&mut conn, &reply);
+                    //通过鉴权的关闭请求
+                    if stop && reply.ok {
+                        self.server_stop();
+                        break 'first_loop;
                     }
                 }
             }
-            app_info.update_current_user();
+            //到期提醒与自动释放
+            app_info.check_expiry(now);
+            //紧急预约抢占
+            app_info.check_preemption(now, gpus.len());
+            //领取超时
+            app_info.check_claims(now);
+            app_info.update_current_user(&gpus, now);
             //设备诊断通知
-            app_info.dialog(&mut gpu);
+            app_info.dialog(&mut gpus, now);
+            app_info.readings = gpus.iter().map(|gpu| gpu.reading()).collect();
+            app_info.record_history(now);
+            app_info.queue = app_info.estimate_queue(now, gpus.len());
+            app_info.check_deadlines();
+            app_info.emit_holder_changes(&holders, now);
+            self.broadcast(&mut subscribers, std::mem::take(&mut app_info.events));
             thread::sleep(time::Duration::from_secs(1));
             //备份
             app_info.write();
         }
+        std::fs::remove_file(&app_info.settings.socket_path).ok();
+        0
     }
 }
 
 #[derive(Debug)]
 pub enum App {
-    User(User),
+    Client(Client),
     Server(Server),
+    Simulate(Simulator),
 }
 impl App {
-    pub fn run(&mut self) {
+    //返回进程退出码
+    pub fn run(&mut self) -> i32 {
         match self {
-            App::User(user) => user.run(),
+            App::Client(client) => client.run(),
             App::Server(server) => server.run(),
+            App::Simulate(simulator) => simulator.run(),
+        }
+    }
+    //本机TCP，无法读取对端uid，以某用户身份操作时需附带API令牌
+    fn tcp_runtime() -> Result<(), String> {
+        let listener = TcpListener::bind(config::TCP_ADDR)
+            .map_err(|err| format!("TCP监听{}失败: {}", config::TCP_ADDR, err))?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !*THREAD_ALIVE.lock().unwrap() {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let slot = match ConnectionSlot::acquire(&CONNECTIONS) {
+                    Some(slot) => slot,
+                    None => {
+                        App::reject(Connection::Tcp(stream));
+                        continue;
+                    }
+                };
+                thread::spawn(move || {
+                    let _slot = slot;
+                    App::serve(Connection::Tcp(stream))
+                });
+            }
+        });
+        Ok(())
+    }
+    //本地套接字，可读取对端uid
+    fn unix_runtime(settings: &config::Settings) -> Result<(), String> {
+        let path = settings.socket_path.clone();
+        let mode = u32::from_str_radix(&settings.socket_mode, 8)
+            .map_err(|_| format!("socket_mode格式错误: {}", settings.socket_mode))?;
+        let dir = std::path::Path::new(&path)
+            .parent()
+            .ok_or(format!("套接字路径错误: {}", path))?;
+        App::socket_dir(dir)?;
+        std::fs::remove_file(&path).ok();
+        let listener = UnixListener::bind(&path)
+            .map_err(|err| format!("本地套接字{}监听失败: {}", path, err))?;
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(mode))
+            .map_err(|err| format!("本地套接字{}权限设置失败: {}", path, err))?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !*THREAD_ALIVE.lock().unwrap() {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let slot = match ConnectionSlot::acquire(&CONNECTIONS) {
+                    Some(slot) => slot,
+                    None => {
+                        App::reject(Connection::Unix(stream));
+                        continue;
+                    }
+                };
+                thread::spawn(move || {
+                    let _slot = slot;
+                    App::serve(Connection::Unix(stream))
+                });
+            }
+        });
+        Ok(())
+    }
+    //套接字所在目录须属于服务端用户且他人不可写，避免套接字被抢先创建或替换
+    fn socket_dir(dir: &std::path::Path) -> Result<(), String> {
+        if !dir.exists() {
+            std::fs::DirBuilder::new()
+                .recursive(true)
+                .mode(0o755)
+                .create(dir)
+                .map_err(|err| format!("套接字目录{}创建失败: {}", dir.display(), err))?;
+        }
+        let metadata = std::fs::symlink_metadata(dir)
+            .map_err(|err| format!("套接字目录{}读取失败: {}", dir.display(), err))?;
+        let euid = unsafe { libc::geteuid() };
+        if !metadata.is_dir() || metadata.uid() != euid || metadata.mode() & 0o022 != 0 {
+            return Err(format!(
+                "套接字目录{}须属于服务端用户且他人不可写！",
+                dir.display()
+            ));
         }
+        Ok(())
     }
-    fn tcp_runtime() {
+    //可选的HTTP接口，供脚本等工具调用
+    fn http_runtime(settings: &config::Settings) -> Result<(), String> {
+        let addr = match &settings.http_addr {
+            Some(addr) if !addr.is_empty() => addr.clone(),
+            _ => return Ok(()),
+        };
+        let listener =
+            TcpListener::bind(&addr).map_err(|err| format!("HTTP监听{}失败: {}", addr, err))?;
+        thread::spawn(move || {
+            for stream in listener.incoming() {
+                if !*THREAD_ALIVE.lock().unwrap() {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                let slot = match ConnectionSlot::acquire(&HTTP_CONNECTIONS) {
+                    Some(slot) => slot,
+                    None => {
+                        App::reject(Connection::Http(stream));
+                        continue;
+                    }
+                };
+                thread::spawn(move || {
+                    let _slot = slot;
+                    App::serve_http(stream)
+                });
+            }
+        });
+        Ok(())
+    }
+    //远程客户端，TLS加密，请求需附带API令牌
+    fn remote_runtime(settings: &config::Settings) -> Result<(), String> {
+        let addr = match &settings.remote_addr {
+            Some(addr) if !addr.is_empty() => addr.clone(),
+            _ => return Ok(()),
+        };
+        let cert = std::fs::read(&settings.tls_cert)
+            .map_err(|err| format!("TLS证书{}读取失败: {}", settings.tls_cert, err))?;
+        let key = std::fs::read(&settings.tls_key)
+            .map_err(|err| format!("TLS私钥{}读取失败: {}", settings.tls_key, err))?;
+        let identity = Identity::from_pkcs8(&cert, &key)
+            .map_err(|err| format!("TLS证书或私钥格式错误: {}", err))?;
+        let acceptor =
+            Arc::new(TlsAcceptor::new(identity).map_err(|err| format!("TLS初始化失败: {}", err))?);
+        let listener =
+            TcpListener::bind(&addr).map_err(|err| format!("远程监听{}失败: {}", addr, err))?;
         thread::spawn(move || {
-            let listener = TcpListener::bind(config::TCP_ADDR).expect("Tcp listen failed");
-            let alive = { *THREAD_ALIVE.lock().unwrap() };
-            while alive {
-                let mut thread_vec: Vec<thread::JoinHandle<()>> = Vec::new();
-                for stream in listener.incoming() {
-                    let stream = stream.expect("failed!");
-                    let handle = thread::spawn(move || {
-                        let mut reader = BufReader::new(&stream);
-                        let mut buffer: Vec<u8> = Vec::new();
-                        reader
-                            .read_until(b'\n', &mut buffer)
-                            .expect("Could not read into buffer");
-                        let info =
-                            str::from_utf8(&buffer).expect("Could not write buffer as string");
-                        let res: UserWrapper = serde_json::from_str(info).unwrap();
-                        let mut lck = RECV_DATA.lock().unwrap();
-                        lck.push(res);
-                    });
-                    thread_vec.push(handle);
-                }
-                for handle in thread_vec {
-                    handle.join().unwrap();
+            for stream in listener.incoming() {
+                if !*THREAD_ALIVE.lock().unwrap() {
+                    break;
+                }
+                let stream = match stream {
+                    Ok(stream) => stream,
+                    Err(_) => continue,
+                };
+                //握手前无法应答，超过上限时直接断开
+                let slot = match ConnectionSlot::acquire(&REMOTE_CONNECTIONS) {
+                    Some(slot) => slot,
+                    None => continue,
+                };
+                let acceptor = acceptor.clone();
+                thread::spawn(move || {
+                    let _slot = slot;
+                    if let Some(stream) = App::accept_tls(&acceptor, stream) {
+                        App::serve(Connection::Tls(Box::new(stream)));
+                    }
+                });
+            }
+        });
+        Ok(())
+    }
+    //TLS握手须在期限内完成，超时后由看门狗关闭连接，逐字节慢速发送的握手同样被断开
+    fn accept_tls(acceptor: &TlsAcceptor, stream: TcpStream) -> Option<TlsStream<TcpStream>> {
+        let watchdog = stream.try_clone().ok()?;
+        let (done, finished) = mpsc::channel::<()>();
+        thread::spawn(move || {
+            let timeout = time::Duration::from_secs(config::REPLY_TIMEOUT_SECONDS);
+            if finished.recv_timeout(timeout) == Err(mpsc::RecvTimeoutError::Timeout) {
+                watchdog.shutdown(std::net::Shutdown::Both).ok();
+            }
+        });
+        let accepted = acceptor.accept(stream).ok();
+        done.send(()).ok();
+        accepted
+    }
+    fn serve_http(stream: TcpStream) {
+        let mut conn = Connection::Http(stream);
+        //请求行、请求头与请求体须在期限内读完，逐字节慢速发送的连接同样被断开
+        let deadline =
+            time::Instant::now() + time::Duration::from_secs(config::REPLY_TIMEOUT_SECONDS);
+        let http_request = match HttpRequest::read(&mut BufReader::new(DeadlineReader {
+            conn: &mut conn,
+            deadline,
+        })) {
+            Ok(http_request) => http_request,
+            Err(reply) => return App::reply(&mut conn, &reply),
+        };
+        match http_request.route() {
+            Ok(Route::Request(request)) => {
+                if let Err(reply) = request.validate() {
+                    return App::reply(&mut conn, &reply);
+                }
+                App::submit(
+                    Envelope::new(request).with_token(http_request.token),
+                    None,
+                    conn,
+                );
+            }
+            Ok(Route::Schema) => {
+                conn.write_all(&http::write_response(200, http::JSON, http::SCHEMA))
+                    .ok();
+            }
+            Ok(Route::Dashboard) => {
+                conn.write_all(&http::write_response(200, http::HTML, http::DASHBOARD))
+                    .ok();
+            }
+            Err(reply) => App::reply(&mut conn, &reply),
+        }
+    }
+    //交给主循环处理；主循环在原连接上应答，客户端不读取应答时写入超时，不阻塞调度
+    fn submit(envelope: Envelope, uid: Option<u32>, conn: Connection) {
+        conn.set_write_timeout(time::Duration::from_secs(config::REPLY_TIMEOUT_SECONDS));
+        RECV_DATA.lock().unwrap().push((envelope, uid, conn));
+    }
+    //连接数已满时回复繁忙，不等待写入
+    fn reject(mut conn: Connection) {
+        conn.set_write_timeout(time::Duration::from_secs(1));
+        App::reply(&mut conn, &App::busy());
+    }
+    fn busy() -> Reply {
+        Reply::error(
+            ErrorCode::Unavailable,
+            String::from("服务端连接数已满，请稍后重试！"),
+        )
+    }
+    //读取请求交给主循环处理，握手与无法解析的请求直接应答
+    fn serve(mut conn: Connection) {
+        let mut greeted = false;
+        loop {
+            //每行须在期限内读完，逐字节慢速发送的连接同样被断开
+            let deadline =
+                time::Instant::now() + time::Duration::from_secs(config::REPLY_TIMEOUT_SECONDS);
+            let line = match protocol::read_line(
+                &mut BufReader::new(DeadlineReader {
+                    conn: &mut conn,
+                    deadline,
+                }),
+                config::MAX_MESSAGE_BYTES,
+            ) {
+                Ok(Some(line)) => line,
+                Ok(None) => return,
+                Err(reply) => return App::reply(&mut conn, &reply),
+            };
+            match protocol::parse(&line) {
+                //每个连接只握手一次，避免反复握手占用连接
+                Ok(envelope) if envelope.request == Request::Hello && greeted => {
+                    let reply = Reply::error(ErrorCode::BadRequest, String::from("重复握手！"));
+                    return App::reply(&mut conn, &reply);
+                }
+                Ok(envelope) if envelope.request == Request::Hello => {
+                    greeted = true;
+                    let reply = Reply::ok(format!("协议版本{}", protocol::VERSION))
+                        .with_data(serde_json::json!({ "version": protocol::VERSION }));
+                    App::reply(&mut conn, &reply);
+                }
+                Ok(envelope) => {
+                    //uid以服务端读取的为准，TCP连接无法确认
+                    let uid = conn.peer_uid();
+                    return App::submit(envelope, uid, conn);
+                }
+                Err(reply) => return App::reply(&mut conn, &reply),
+            }
+        }
+    }
+    fn reply(conn: &mut Connection, reply: &Reply) {
+        if let Connection::Http(stream) = conn {
+            stream.write_all(&http::response(reply)).ok();
+            return;
+        }
+        let mut line = serde_json::to_string(reply).unwrap();
+        line.push('\n');
+        conn.write_all(line.as_bytes()).ok();
+    }
+}
+
+//客户端连接
+enum Connection {
+    Tcp(TcpStream),
+    Unix(UnixStream),
+    Http(TcpStream),                //应答写为HTTP响应
+    Tls(Box<TlsStream<TcpStream>>), //远程客户端
+}
+impl Connection {
+    //通过SO_PEERCRED读取本地套接字对端的uid
+    fn peer_uid(&self) -> Option<u32> {
+        let stream = match self {
+            Connection::Unix(stream) => stream,
+            Connection::Tcp(_) | Connection::Http(_) | Connection::Tls(_) => return None,
+        };
+        let mut cred = libc::ucred {
+            pid: 0,
+            uid: 0,
+            gid: 0,
+        };
+        let mut len = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+        let ret = unsafe {
+            libc::getsockopt(
+                stream.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_PEERCRED,
+                &mut cred as *mut libc::ucred as *mut libc::c_void,
+                &mut len,
+            )
+        };
+        if ret == 0 {
+            Some(cred.uid)
+        } else {
+            None
+        }
+    }
+}
+//连接计数，所在计数池超过MAX_CONNECTIONS时拒绝新连接，处理线程结束时归还
+struct ConnectionSlot(&'static Mutex<usize>);
+impl ConnectionSlot {
+    fn acquire(pool: &'static Mutex<usize>) -> Option<ConnectionSlot> {
+        let mut count = pool.lock().unwrap();
+        if *count >= config::MAX_CONNECTIONS {
+            return None;
+        }
+        *count += 1;
+        Some(ConnectionSlot(pool))
+    }
+}
+impl Drop for ConnectionSlot {
+    fn drop(&mut self) {
+        *self.0.lock().unwrap() -= 1;
+    }
+}
+//事件订阅者，由单独的写线程推送事件并探测连接是否断开
+struct Subscriber {
+    filter: Option<String>,
+    sender: Option<mpsc::SyncSender<String>>, //积压过多时置空，写线程随之结束
+    writer: thread::JoinHandle<()>,
+}
+impl Subscriber {
+    fn spawn(filter: Option<String>, mut conn: Connection) -> Subscriber {
+        let (sender, receiver) = mpsc::sync_channel::<String>(config::SUBSCRIBER_QUEUE);
+        let writer = thread::spawn(move || loop {
+            match receiver.recv_timeout(time::Duration::from_secs(1)) {
+                Ok(line) => {
+                    if conn.write_all(line.as_bytes()).is_err() {
+                        break;
+                    }
                 }
+                //没有事件时每秒探测一次，对端关闭即结束
+                Err(mpsc::RecvTimeoutError::Timeout) => {
+                    if conn.is_closed() {
+                        break;
+                    }
+                }
+                Err(mpsc::RecvTimeoutError::Disconnected) => break,
             }
         });
+        Subscriber {
+            filter,
+            sender: Some(sender),
+            writer,
+        }
+    }
+    //不阻塞投递，读取过慢的订阅者被断开
+    fn send(&mut self, line: String) {
+        if let Some(sender) = &self.sender {
+            if sender.try_send(line).is_err() {
+                self.sender = None;
+            }
+        }
+    }
+}
+
+impl Connection {
+    fn is_remote(&self) -> bool {
+        matches!(self, Connection::Tls(_))
+    }
+    fn set_read_timeout(&self, timeout: time::Duration) {
+        match self {
+            Connection::Tcp(stream) | Connection::Http(stream) => {
+                stream.set_read_timeout(Some(timeout)).ok();
+            }
+            Connection::Tls(stream) => {
+                stream.get_ref().set_read_timeout(Some(timeout)).ok();
+            }
+            Connection::Unix(stream) => {
+                stream.set_read_timeout(Some(timeout)).ok();
+            }
+        }
+    }
+    fn set_write_timeout(&self, timeout: time::Duration) {
+        match self {
+            Connection::Tcp(stream) | Connection::Http(stream) => {
+                stream.set_write_timeout(Some(timeout)).ok();
+            }
+            Connection::Tls(stream) => {
+                stream.get_ref().set_write_timeout(Some(timeout)).ok();
+            }
+            Connection::Unix(stream) => {
+                stream.set_write_timeout(Some(timeout)).ok();
+            }
+        }
+    }
+}
+impl Connection {
+    fn set_nonblocking(&self, nonblocking: bool) {
+        match self {
+            Connection::Tcp(stream) | Connection::Http(stream) => {
+                stream.set_nonblocking(nonblocking).ok();
+            }
+            Connection::Tls(stream) => {
+                stream.get_ref().set_nonblocking(nonblocking).ok();
+            }
+            Connection::Unix(stream) => {
+                stream.set_nonblocking(nonblocking).ok();
+            }
+        }
+    }
+    //非阻塞读取一次，读到结束或出错视为对端已关闭；订阅后客户端不再发送数据，读到的内容直接丢弃
+    fn is_closed(&mut self) -> bool {
+        self.set_nonblocking(true);
+        let mut buf = [0; 256];
+        let closed = match self.read(&mut buf) {
+            Ok(n) => n == 0,
+            Err(err) => err.kind() != std::io::ErrorKind::WouldBlock,
+        };
+        self.set_nonblocking(false);
+        closed
+    }
+}
+//按整行期限读取，每次读取前把超时设为剩余时间
+struct DeadlineReader<'a> {
+    conn: &'a mut Connection,
+    deadline: time::Instant,
+}
+impl Read for DeadlineReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let remaining = self
+            .deadline
+            .saturating_duration_since(time::Instant::now());
+        if remaining.is_zero() {
+            return Err(std::io::ErrorKind::TimedOut.into());
+        }
+        self.conn.set_read_timeout(remaining);
+        self.conn.read(buf)
+    }
+}
+impl Read for Connection {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) | Connection::Http(stream) => stream.read(buf),
+            Connection::Unix(stream) => stream.read(buf),
+            Connection::Tls(stream) => stream.read(buf),
+        }
+    }
+}
+impl Write for Connection {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        match self {
+            Connection::Tcp(stream) | Connection::Http(stream) => stream.write(buf),
+            Connection::Unix(stream) => stream.write(buf),
+            Connection::Tls(stream) => stream.write(buf),
+        }
+    }
+    fn flush(&mut self) -> std::io::Result<()> {
+        match self {
+            Connection::Tcp(stream) | Connection::Http(stream) => stream.flush(),
+            Connection::Unix(stream) => stream.flush(),
+            Connection::Tls(stream) => stream.flush(),
+        }
     }
 }
 impl Drop for App {
@@ -199,192 +866,2897 @@ impl Drop for App {
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
 struct UserWrapper {
+    #[serde(default)]
+    id: u64, //预约编号，由服务端分配
     urg: bool,
     finish: bool,
     timestamp: i64,
     email: String,
     date_time: String,
+    #[serde(default)]
+    duration: Option<i64>,
+    #[serde(default)]
+    grant_time: Option<String>, //分配到显卡的时刻，由服务端填写
+    #[serde(default)]
+    expiry_notified: bool,
+    #[serde(default)]
+    slot: bool,
+    #[serde(default)]
+    early: bool,
+    #[serde(default)]
+    pending: bool, //等待管理员审批
+    #[serde(default)]
+    preempt_at: Option<String>, //被紧急预约抢占的时刻，由服务端填写
+    #[serde(default)]
+    requeued: bool, //被抢占后重新排队，优先于普通预约
+    #[serde(default)]
+    series: Option<u64>, //由周期预约展开的条目
+    #[serde(default)]
+    deadline: Option<String>, //任务需在该时刻前完成
+    #[serde(default)]
+    deadline_warned: bool,
+    #[serde(default)]
+    uid: Option<u32>, //提交请求的本地用户，由服务端通过本地套接字读取
+    #[serde(default)]
+    offered_at: Option<String>, //轮到该预约但尚未领取，由服务端填写
+    #[serde(default)]
+    no_shows: u32, //未按时领取的次数，大于0时排在其他预约之后
+    #[serde(default)]
+    order: Option<usize>, //管理员调整后的排队位置，优先于自动排序
 }
 impl From<User> for UserWrapper {
     fn from(user: User) -> Self {
         let date_time: String = user.date_time.to_string();
         UserWrapper {
+            id: 0,
             urg: user.urg,
-            finish: user.finish,
+            finish: false,
             timestamp: user.timestamp,
             email: user.email,
-            date_time: date_time,
+            date_time,
+            duration: user.duration,
+            grant_time: None,
+            expiry_notified: false,
+            slot: user.slot,
+            early: user.early,
+            pending: false,
+            preempt_at: None,
+            requeued: false,
+            series: None,
+            deadline: user.deadline.map(|deadline| deadline.to_string()),
+            deadline_warned: false,
+            uid: None,
+            offered_at: None,
+            no_shows: 0,
+            order: None,
         }
     }
 }
-impl Into<User> for UserWrapper {
-    fn into(self) -> User {
-        let date_time: NaiveDateTime =
-            NaiveDateTime::parse_from_str(self.date_time.as_str(), "%Y-%m-%d %H:%M:%S").unwrap();
-        User {
-            urg: self.urg,
-            finish: self.finish,
-            timestamp: self.timestamp,
-            email: self.email,
-            date_time: date_time,
+impl From<Booking> for UserWrapper {
+    fn from(booking: Booking) -> Self {
+        UserWrapper {
+            id: 0,
+            urg: false,
+            finish: false,
+            timestamp: 0, //由服务端按受理时刻填写
+            email: booking.email,
+            date_time: booking.date_time,
+            duration: booking.duration,
+            grant_time: None,
+            expiry_notified: false,
+            slot: booking.slot,
+            early: booking.early,
+            pending: false,
+            preempt_at: None,
+            requeued: false,
+            series: None,
+            deadline: booking.deadline,
+            deadline_warned: false,
+            uid: None,
+            offered_at: None,
+            no_shows: 0,
+            order: None,
+        }
+    }
+}
+impl UserWrapper {
+    fn date_time(&self) -> NaiveDateTime {
+        NaiveDateTime::parse_from_str(self.date_time.as_str(), "%Y-%m-%d %H:%M:%S").unwrap()
+    }
+    //时段预约的区间[开始, 结束)
+    fn slot(&self) -> Option<(NaiveDateTime, NaiveDateTime)> {
+        if !self.slot {
+            return None;
+        }
+        let start = self.date_time();
+        Some((start, start + Duration::minutes(self.duration?)))
+    }
+    //用于通知邮件的预约描述
+    fn describe(&self) -> String {
+        let mut desc = if self.urg {
+            String::from("紧急预约")
+        } else {
+            format! {"{}开始的预约",self.date_time}
+        };
+        if let Some(duration) = self.duration {
+            desc.push_str(&format! {"，时长{}分钟",duration});
         }
+        if let Some(deadline) = &self.deadline {
+            desc.push_str(&format! {"，截止{}",deadline});
+        }
+        desc
+    }
+    fn deadline(&self) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(self.deadline.as_ref()?, "%Y-%m-%d %H:%M:%S").ok()
+    }
+    //松弛时间：现在开始运行时距截止时刻的余量，为负则已无法按时完成
+    fn slack(&self, now: NaiveDateTime) -> Option<Duration> {
+        Some(self.deadline()? - now - Duration::minutes(self.duration.unwrap_or(0)))
+    }
+    fn grant_time(&self) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(self.grant_time.as_ref()?, "%Y-%m-%d %H:%M:%S").ok()
+    }
+    //持有状态描述
+    fn holding(&self) -> String {
+        let mut desc = format! {"持有者{}(预约{})",self.email,self.id};
+        if let Some(offered_at) = &self.offered_at {
+            desc.push_str(&format! {"，{}轮到，待领取",offered_at});
+        } else if let Some(grant_time) = &self.grant_time {
+            desc.push_str(&format! {"，{}分配",grant_time});
+        }
+        if let Some(end_time) = self.end_time() {
+            desc.push_str(&format! {"，{}到期",end_time});
+        }
+        desc
+    }
+    fn offered_at(&self) -> Option<NaiveDateTime> {
+        NaiveDateTime::parse_from_str(self.offered_at.as_ref()?, "%Y-%m-%d %H:%M:%S").ok()
     }
+    //预约到期时刻：时段预约为时段结束，否则为分配时刻加预计时长
+    fn end_time(&self) -> Option<NaiveDateTime> {
+        if let Some((_, end)) = self.slot() {
+            return Some(end);
+        }
+        Some(self.grant_time()? + Duration::minutes(self.duration?))
+    }
+}
+
+//强制释放记录
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Release {
+    email: String,
+    device: u32,
+    time: String,
+    reason: String,
+}
+
+//一次显卡读数采样，用于面板曲线
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Sample {
+    time: String,
+    readings: Vec<nvidia::Reading>,
+}
+
+//远程连接的用户API令牌
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct ApiToken {
+    email: String,
+    issued: String,
+}
+
+//被拒绝的管理操作记录
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Denial {
+    time: String,
+    uid: Option<u32>,
+    command: String,
+}
+
+//周期预约，创建时展开为具体的各次预约
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Series {
+    email: String,
+    rule: Recurrence,
+    duration: i64,
+    #[serde(default)]
+    until: String,
+}
+
+//用户历史用量，按半衰期衰减
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Usage {
+    gpu_hours: f64,
+    updated: String,
+    #[serde(default)]
+    holds: u64, //累计持有次数，用于估计持有时长
+    #[serde(default)]
+    hold_minutes: i64,
+}
+
+//排队用户的位置与预计开始时刻
+#[derive(Serialize, Deserialize, Debug, Clone)]
+struct Estimate {
+    id: u64,
+    email: String,
+    position: usize,
+    eta: Option<String>,
 }
 
 use std::collections::BTreeMap;
 #[derive(Serialize, Deserialize, Debug)]
 struct AppInfo {
     server_info: Server,
-    curr_user: Option<UserWrapper>,
-    user_info: BTreeMap<String, UserWrapper>, //使用email到info到映射
+    #[serde(default)]
+    curr_users: BTreeMap<u32, UserWrapper>, //显卡序号到持有者的映射
+    #[serde(default)]
+    reservations: BTreeMap<u64, UserWrapper>, //预约编号到预约的映射
+    #[serde(default)]
+    next_id: u64,
+    #[serde(default, skip_serializing)]
+    user_info: BTreeMap<String, UserWrapper>, //旧版本以email为键的预约表，加载时迁移
+    #[serde(default, skip_serializing)]
+    curr_user: Option<UserWrapper>, //旧版本的唯一持有者，加载时迁移为显卡0的持有者
+    #[serde(default)]
+    usage: BTreeMap<String, Usage>, //email到历史用量的映射
+    #[serde(default)]
+    releases: Vec<Release>,
+    #[serde(default)]
+    denials: Vec<Denial>,
+    #[serde(default, skip_serializing)]
+    tokens: BTreeMap<String, ApiToken>, //旧版本以明文令牌为键的映射，加载时迁移
+    #[serde(default)]
+    token_hashes: BTreeMap<String, ApiToken>, //API令牌的SHA-256摘要到所属用户的映射，不保存明文
+    #[serde(default)]
+    queue: Vec<Estimate>, //排队快照，供客户端查看
+    #[serde(default)]
+    series: BTreeMap<u64, Series>, //周期预约编号到周期预约的映射
+    #[serde(default)]
+    next_series_id: u64,
+    #[serde(default)]
+    readings: Vec<nvidia::Reading>, //最新的显卡读数
+    #[serde(skip)]
+    history: VecDeque<Sample>, //最近的读数采样，重启后重新记录
+    #[serde(skip)]
+    events: Vec<Event>, //待推送给订阅者的事件
+    #[serde(skip)]
+    settings: config::Settings,
+    #[serde(skip)]
+    dry_run: bool, //模拟运行时不发送邮件
 }
 lazy_static! {
-    static ref LAST_TIME: Mutex<Option<NaiveTime>> = Mutex::new(None);
-    static ref TIME_GAP: Mutex<Duration> = Mutex::new(Duration::seconds(config::TIME_GAP_SECONDS));
+    //各显卡独立的诊断计时
+    static ref LAST_TIME: Mutex<BTreeMap<u32, NaiveTime>> = Mutex::new(BTreeMap::new());
+    static ref TIME_GAP: Mutex<BTreeMap<u32, Duration>> = Mutex::new(BTreeMap::new());
+    //空闲通知后开始计时，设备恢复使用时清除
+    static ref IDLE_WARNED: Mutex<BTreeMap<u32, NaiveDateTime>> = Mutex::new(BTreeMap::new());
+    //邮件由发送线程依次投递，主循环不等待SMTP
+    static ref MAILER: Mutex<mpsc::Sender<Mail>> = {
+        let (sender, receiver) = mpsc::channel::<Mail>();
+        thread::spawn(move || {
+            for mail in receiver {
+                mail.deliver();
+            }
+        });
+        Mutex::new(sender)
+    };
+}
+
+//待发送的邮件
+struct Mail {
+    account: String,
+    password: String,
+    receiver: String,
+    subject: String,
+    body: String,
+}
+impl Mail {
+    //临时失败时按指数退避重试，超过重试次数后放弃并记录；收件人被拒等永久错误重试也无用，直接放弃，不拖延后续邮件
+    fn deliver(&self) {
+        let mailer = match SmtpTransport::relay(config::SERVER) {
+            Ok(relay) => relay
+                .credentials(Credentials::new(
+                    self.account.clone(),
+                    self.password.clone(),
+                ))
+                .build(),
+            Err(err) => return eprintln!("邮件服务器配置错误: {}", err),
+        };
+        let message = match (
+            format!("<{}>", self.account).parse(),
+            format!("<{}>", self.receiver).parse(),
+        ) {
+            (Ok(from), Ok(to)) => Message::builder()
+                .from(from)
+                .to(to)
+                .subject(self.subject.as_str())
+                .body(self.body.clone()),
+            _ => return eprintln!("邮件地址格式错误: {}", self.receiver),
+        };
+        let message = match message {
+            Ok(message) => message,
+            Err(err) => return eprintln!("邮件构造失败: {}", err),
+        };
+        for attempt in 0..=config::MAIL_RETRIES {
+            match mailer.send(&message) {
+                Ok(_) => return,
+                Err(err) if err.is_permanent() => {
+                    return eprintln!(
+                        "邮件被服务器拒绝，不再重试: {} {}: {}",
+                        self.receiver, self.subject, err
+                    )
+                }
+                Err(_) if attempt < config::MAIL_RETRIES => thread::sleep(
+                    time::Duration::from_secs(config::MAIL_RETRY_SECONDS << attempt),
+                ),
+                Err(err) => eprintln!(
+                    "邮件发送失败，已放弃: {} {}: {}",
+                    self.receiver, self.subject, err
+                ),
+            }
+        }
+    }
 }
 impl AppInfo {
-    fn load() -> AppInfo {
-        //文件不存在，构造对象，否则使用加载
-        let info: AppInfo = AppInfo {
+    fn new() -> AppInfo {
+        AppInfo {
             server_info: Server::new(String::from(""), String::from("")),
-            curr_user: None,
+            curr_users: BTreeMap::new(),
+            reservations: BTreeMap::new(),
+            next_id: 0,
             user_info: BTreeMap::new(),
-        };
-        match std::fs::read_to_string(config::INFO_FILE) {
-            Ok(data) => serde_json::from_str(&data).unwrap(),
-            Err(_) => info,
+            curr_user: None,
+            usage: BTreeMap::new(),
+            releases: Vec::new(),
+            denials: Vec::new(),
+            tokens: BTreeMap::new(),
+            token_hashes: BTreeMap::new(),
+            queue: Vec::new(),
+            series: BTreeMap::new(),
+            next_series_id: 0,
+            readings: Vec::new(),
+            history: VecDeque::new(),
+            events: Vec::new(),
+            settings: config::Settings::default(),
+            dry_run: false,
         }
     }
-
-    fn write(&self) {
-        let mut writer = std::fs::File::create(config::INFO_FILE).unwrap();
-        let info = serde_json::to_string(self).unwrap();
-        writer.write_all(info.as_bytes()).unwrap();
+    fn load() -> AppInfo {
+        //文件不存在，构造对象，否则使用加载
+        let mut info: AppInfo = match std::fs::read_to_string(config::INFO_FILE) {
+            Ok(data) => serde_json::from_str(&data).unwrap(),
+            Err(_) => AppInfo::new(),
+        };
+        info.migrate();
+        info
+    }
+    //迁移旧版本info.json中的字段
+    fn migrate(&mut self) {
+        if let Some(holder) = self.curr_user.take() {
+            self.curr_users.entry(0).or_insert(holder);
+        }
+        //旧版本的预约逐条分配编号，持有者按email对应
+        for (_, user) in std::mem::take(&mut self.user_info) {
+            let id = self.add_reservation(user);
+            let email = self.reservations[&id].email.clone();
+            for holder in self.curr_users.values_mut() {
+                if holder.id == 0 && holder.email == email {
+                    holder.id = id;
+                }
+            }
+        }
+        for (token, owner) in std::mem::take(&mut self.tokens) {
+            self.token_hashes.insert(Util::hash_token(&token), owner);
+        }
+    }
+    fn add_reservation(&mut self, mut user: UserWrapper) -> u64 {
+        self.next_id += 1;
+        user.id = self.next_id;
+        self.reservations.insert(user.id, user);
+        self.next_id
     }
 
-    fn update_current_user(&mut self) {
-        //同步map内容到curr_user
-        if self.curr_user.is_some() {
-            self.curr_user = Some(
-                self.user_info
-                    .get(&self.curr_user.as_ref().unwrap().email)
-                    .unwrap()
-                    .clone(),
+    //含令牌摘要与用户信息，仅所有者可读写
+    fn write(&self) {
+        let mut writer = std::fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(true)
+            .mode(0o600)
+            .open(config::INFO_FILE)
+            .unwrap();
+        //旧版本创建的文件权限可能过宽
+        writer
+            .set_permissions(std::fs::Permissions::from_mode(0o600))
+            .ok();
+        let info = serde_json::to_string(self).unwrap();
+        writer.write_all(info.as_bytes()).unwrap();
+    }
+
+    //处理一条客户端请求
+    fn handle(
+        &mut self,
+        request: Request,
+        uid: Option<u32>,
+        devices: usize,
+        now: NaiveDateTime,
+    ) -> Reply {
+        //时刻与时长越界会在后续计算中panic，先于处理校验
+        if let Err(reply) = request.validate() {
+            return reply;
+        }
+        //排队时间戳取服务端的受理时刻，客户端无法借此插队
+        let submitted = Local
+            .from_local_datetime(&now)
+            .earliest()
+            .map_or(0, |now| now.timestamp());
+        let booking = |booking: Booking, urg: bool| {
+            let mut user = UserWrapper::from(booking);
+            user.urg = urg;
+            user.timestamp = submitted;
+            user.uid = uid;
+            user
+        };
+        match request {
+            Request::Book(x) => self.handle_book(booking(x, false), devices, now),
+            Request::Urgent(x) => self.handle_book(booking(x, true), devices, now),
+            Request::Repeat {
+                booking: x,
+                rule,
+                until,
+            } => self.handle_repeat(booking(x, false), rule, &until, devices),
+            Request::Finish { email, id } => self.handle_finish(&email, id.unwrap_or(0), uid),
+            Request::Cancel {
+                email,
+                series,
+                date,
+            } => self.handle_cancel(&email, series, date, uid),
+            Request::Claim { email, id } => self.handle_claim(&email, id.unwrap_or(0), uid, now),
+            Request::Modify {
+                email,
+                id,
+                duration,
+                start,
+            } => match self.find_target(&email, id.unwrap_or(0), "modify", uid) {
+                Ok(id) => self.handle_modify(&email, id, duration, start, devices, now),
+                Err(reply) => reply,
+            },
+            Request::Query { email, query } => self.handle_query(&query, &email, uid, devices, now),
+            Request::Admin { token, command } => {
+                if !self.authorize(uid, token.as_deref()) {
+                    self.deny(uid, &command, now);
+                    return Reply::error(ErrorCode::Forbidden, String::from("无权执行管理操作！"));
+                }
+                match command {
+                    AdminCommand::Stop => Reply::ok(String::from("服务已关闭！")),
+                    AdminCommand::Approve { admin, id } => self.handle_approval(&admin, id, true),
+                    AdminCommand::Reject { admin, id } => self.handle_approval(&admin, id, false),
+                    AdminCommand::Release { id } => self.handle_release(id, now),
+                    AdminCommand::Reorder { id, position } => {
+                        self.handle_reorder(id, position, now)
+                    }
+                    AdminCommand::IssueToken { email } => self.issue_token(&email, now),
+                    AdminCommand::RevokeToken { email } => self.revoke_tokens(&email),
+                }
+            }
+            Request::Hello | Request::Watch { .. } => {
+                Reply::error(ErrorCode::BadRequest, String::from("请求格式错误！"))
+            }
+        }
+    }
+    //uid在白名单内，或令牌与仅所有者可读的令牌文件一致
+    fn authorize(&self, uid: Option<u32>, token: Option<&str>) -> bool {
+        if uid.is_some_and(|uid| self.settings.admin_uids.contains(&uid)) {
+            return true;
+        }
+        let token = match token {
+            Some(token) if !token.is_empty() => token,
+            _ => return false,
+        };
+        let path = &self.settings.admin_token_file;
+        match std::fs::metadata(path) {
+            Ok(meta) if meta.permissions().mode() & 0o077 == 0 => {}
+            Ok(_) => {
+                eprintln!("管理员令牌文件{}权限过宽，已忽略令牌", path);
+                return false;
+            }
+            Err(_) => return false,
+        }
+        std::fs::read_to_string(path).is_ok_and(|expected| expected.trim() == token)
+    }
+    fn deny(&mut self, uid: Option<u32>, command: &AdminCommand, now: NaiveDateTime) {
+        let uid_text = uid.map_or(String::from("未知"), |uid| uid.to_string());
+        eprintln!(
+            "{} 拒绝未授权的管理操作{:?}，uid: {}",
+            now, command, uid_text
+        );
+        self.denials.push(Denial {
+            time: now.to_string(),
+            uid,
+            command: format!("{:?}", command),
+        });
+        //未授权用户可反复请求，只保留最近的记录
+        let excess = self.denials.len().saturating_sub(config::MAX_DENIALS);
+        self.denials.drain(..excess);
+    }
+    fn issue_token(&mut self, email: &str, now: NaiveDateTime) -> Reply {
+        let token = match Util::random_token(config::API_TOKEN_BYTES) {
+            Ok(token) => token,
+            Err(_) => return Reply::error(ErrorCode::Unavailable, String::from("无法生成令牌！")),
+        };
+        self.token_hashes.insert(
+            Util::hash_token(&token),
+            ApiToken {
+                email: email.to_string(),
+                issued: now.to_string(),
+            },
+        );
+        Reply::ok(format! {"已为用户{}签发API令牌: {}\n请保存到用户主目录下的{}，或设置环境变量RUSTTIP_TOKEN",
+        email,token,config::CLIENT_TOKEN_FILE})
+        .with_data(serde_json::json!({ "email": email, "token": token }))
+    }
+    fn revoke_tokens(&mut self, email: &str) -> Reply {
+        let count = self.token_hashes.len();
+        self.token_hashes.retain(|_, token| token.email != email);
+        match count - self.token_hashes.len() {
+            0 => Reply::error(ErrorCode::NotFound, format! {"用户{}没有API令牌！",email}),
+            n => Reply::ok(format! {"已吊销用户{}的{}个API令牌！",email,n}),
+        }
+    }
+    //持令牌的请求只能以令牌所属用户的身份执行，管理操作仍需管理员令牌
+    fn check_token(&self, request: &Request, token: Option<&str>) -> Result<(), Reply> {
+        let owner = match token.and_then(|token| self.token_hashes.get(&Util::hash_token(token))) {
+            Some(owner) => &owner.email,
+            None => {
+                return Err(Reply::error(
+                    ErrorCode::Forbidden,
+                    String::from("无法确认请求者身份，需要有效的API令牌！"),
+                ))
+            }
+        };
+        match request.email() {
+            Some(email) if email != owner => Err(Reply::error(
+                ErrorCode::Forbidden,
+                format! {"令牌属于用户{}，无权以{}的身份操作！",owner,email},
+            )),
+            _ => Ok(()),
+        }
+    }
+    //远程连接一律校验令牌；本机TCP与HTTP连接读不到uid，以某用户身份操作时同样需要令牌
+    fn check_identity(
+        &self,
+        request: &Request,
+        uid: Option<u32>,
+        remote: bool,
+        token: Option<&str>,
+    ) -> Result<(), Reply> {
+        if remote || (uid.is_none() && request.email().is_some()) {
+            return self.check_token(request, token);
+        }
+        Ok(())
+    }
+    //强制释放预约持有或待领取的显卡，预约随之注销
+    fn handle_release(&mut self, id: u64, now: NaiveDateTime) -> Reply {
+        let index = match self.curr_users.iter().find(|(_, holder)| holder.id == id) {
+            Some((index, _)) => *index,
+            None => return Reply::error(ErrorCode::NotFound, format! {"预约{}未持有显卡！",id}),
+        };
+        let email = match self.reservations.get_mut(&id) {
+            Some(x) => {
+                x.finish = true;
+                x.email.clone()
+            }
+            None => return Reply::error(ErrorCode::NotFound, format! {"预约{}不存在！",id}),
+        };
+        self.record_release(Release {
+            email: email.clone(),
+            device: index,
+            time: now.to_string(),
+            reason: String::from("admin"),
+        });
+        self.send_email(
+            email.clone(),
+            "显卡强制释放通知",
+            &format! {"用户{}的预约{}占用的显卡{}已被管理员强制释放！",email,id,index},
+        );
+        Reply::ok(format! {"已释放用户{}的预约{}占用的显卡{}！",email,id,index}).with_id(id)
+    }
+    //移到指定位置，固定其前方的顺序，其余预约仍自动排序
+    fn handle_reorder(&mut self, id: u64, position: usize, now: NaiveDateTime) -> Reply {
+        let mut waiting: Vec<u64> = self.waiting(now).iter().map(|user| user.id).collect();
+        let from = match waiting.iter().position(|x| *x == id) {
+            Some(from) => from,
+            None => return Reply::error(ErrorCode::NotFound, format! {"预约{}不在排队中！",id}),
+        };
+        if position == 0 {
+            return Reply::error(ErrorCode::BadRequest, String::from("排队位置从1开始！"));
+        }
+        let target = position.min(waiting.len());
+        waiting.remove(from);
+        waiting.insert(target - 1, id);
+        //此前调整留下的位置一并清除，只保留本次的排序
+        for user in self.reservations.values_mut() {
+            user.order = None;
+        }
+        for (i, x) in waiting.iter().enumerate().take(target) {
+            if let Some(user) = self.reservations.get_mut(x) {
+                user.order = Some(i + 1);
+            }
+        }
+        //时段预约与未领取记录的排序先于调整位置，以重新排序后的实际位置应答
+        let actual = self
+            .waiting(now)
+            .iter()
+            .position(|user| user.id == id)
+            .map_or(target, |x| x + 1);
+        let body = if actual == target {
+            format! {"预约{}已移到排队位置{}！",id,actual}
+        } else {
+            format! {"预约{}已移到排队位置{}，时段预约与未领取记录优先，无法排到第{}位！",id,actual,target}
+        };
+        Reply::ok(body).with_id(id).with_position(Some(actual))
+    }
+    fn handle_book(&mut self, mut user: UserWrapper, devices: usize, now: NaiveDateTime) -> Reply {
+        //时段冲突的预约直接拒绝
+        if let Some(body) = self.check_conflict(&user, devices) {
+            return Reply::error(ErrorCode::Conflict, body);
+        }
+        user.pending = self.need_approval(&user);
+        let id = self.add_reservation(user.clone());
+        let state = if user.pending { "，等待审批" } else { "" };
+        self.events.push(
+            Event::new(
+                EventKind::Booked,
+                now.to_string(),
+                format! {"用户{}的预约{}已受理{}: {}",user.email,id,state,user.describe()},
+            )
+            .with_user(&user.email, id),
+        );
+        //邮件通知
+        if user.pending {
+            let body =
+                format! {"用户{}的预约{}需要管理员审批，审批结果将邮件通知您！",user.email,id};
+            self.send_email(user.email.clone(), "预约审批通知", &body);
+            for admin in self.settings.admins.clone() {
+                self.send_email(
+                    admin.clone(),
+                    "预约待审批",
+                    &format! {"用户{}提交了{}(编号{})，请审批：\n同意: RustTip approve {} {}\n拒绝: RustTip reject {} {}",
+                    user.email,user.describe(),id,admin,id,admin,id},
+                );
+            }
+            Reply::ok(body).with_id(id)
+        } else {
+            let mut body =
+                format! {"用户{}预约成功，预约编号{}！服务器就绪后将自动通知您！",user.email,id};
+            let estimates = self.estimate_queue(now, devices);
+            let estimate = estimates.iter().find(|x| x.id == id);
+            if let Some(estimate) = estimate {
+                body.push_str(&format! {"当前排队第{}位，",estimate.position});
+                match &estimate.eta {
+                    Some(eta) => body.push_str(&format! {"预计{}开始。",eta}),
+                    None => body.push_str("暂无法估计开始时刻。"),
+                }
+            }
+            self.send_email(user.email.clone(), "服务器预约通知", &body);
+            Reply::ok(body)
+                .with_id(id)
+                .with_position(estimate.map(|x| x.position))
+        }
+    }
+    fn handle_query(
+        &self,
+        query: &Query,
+        email: &str,
+        uid: Option<u32>,
+        devices: usize,
+        now: NaiveDateTime,
+    ) -> Reply {
+        match query {
+            Query::Status => {
+                let mut lines: Vec<String> = Vec::new();
+                for reading in self.readings.iter() {
+                    let mut line = format! {"显卡{}: 利用率{}%，显存{}/{}MB，",
+                    reading.index,reading.use_ratio,reading.used_memory,reading.total_memory};
+                    match self.curr_users.get(&reading.index) {
+                        Some(holder) => line.push_str(&holder.holding()),
+                        None => line.push_str("空闲"),
+                    }
+                    lines.push(line);
+                }
+                lines.push(format! {"排队{}人",self.estimate_queue(now, devices).len()});
+                Reply::ok(lines.join("\n")).with_data(serde_json::json!({
+                    "readings": self.readings,
+                    "holders": self.curr_users,
+                }))
+            }
+            Query::Queue => {
+                let estimates = self.estimate_queue(now, devices);
+                let mut lines: Vec<String> = Vec::new();
+                for estimate in estimates.iter() {
+                    let user = &self.reservations[&estimate.id];
+                    lines.push(format! {"{}. {} 预约{}: {}，预计{}开始",
+                    estimate.position,user.email,user.id,user.describe(),
+                    estimate.eta.as_deref().unwrap_or("未知")});
+                }
+                let pending: Vec<&UserWrapper> =
+                    self.reservations.values().filter(|x| x.pending).collect();
+                for user in pending.iter() {
+                    lines.push(format! {"待审批 {} 预约{}: {}",user.email,user.id,user.describe()});
+                }
+                if lines.is_empty() {
+                    lines.push(String::from("当前无人排队"));
+                }
+                Reply::ok(lines.join("\n")).with_data(serde_json::json!({
+                    "queue": estimates,
+                    "pending": pending,
+                }))
+            }
+            Query::Whoami => {
+                let estimates = self.estimate_queue(now, devices);
+                let mine: Vec<&UserWrapper> = self
+                    .reservations
+                    .values()
+                    .filter(|x| x.email == email && !x.finish)
+                    .collect();
+                let mut lines = vec![format! {"用户{}共有{}个预约",email,mine.len()}];
+                if let Some(uid) = uid {
+                    lines.insert(0, format! {"本地用户uid: {}",uid});
+                }
+                for user in mine.iter() {
+                    let held = self.curr_users.iter().find(|(_, x)| x.id == user.id);
+                    let state = if let Some((index, holder)) = held {
+                        format! {"显卡{}，{}",index,holder.holding()}
+                    } else if user.pending {
+                        String::from("待审批")
+                    } else if let Some(estimate) = estimates.iter().find(|x| x.id == user.id) {
+                        format! {"排队第{}位，预计{}开始",
+                        estimate.position,estimate.eta.as_deref().unwrap_or("未知")}
+                    } else {
+                        String::from("等待开始")
+                    };
+                    lines.push(format! {"预约{}: {}，{}",user.id,user.describe(),state});
+                }
+                Reply::ok(lines.join("\n")).with_data(serde_json::json!({
+                    "email": email,
+                    "uid": uid,
+                    "reservations": mine,
+                }))
+            }
+            Query::Dashboard => {
+                let upcoming = self.upcoming(now);
+                let queue: Vec<Estimate> = self
+                    .estimate_queue(now, devices)
+                    .into_iter()
+                    .filter(|x| upcoming.iter().all(|user| user.id != x.id))
+                    .collect();
+                let pending = self.reservations.values().filter(|x| x.pending).count();
+                let body = format! {"持有{}张显卡，排队{}人，待审批{}个，即将开始的时段预约{}个",
+                self.curr_users.len(),queue.len(),pending,upcoming.len()};
+                Reply::ok(body).with_data(serde_json::json!({
+                    "time": now.to_string(),
+                    "readings": self.readings,
+                    "holders": self.curr_users,
+                    "queue": queue,
+                    "pending": pending,
+                    "upcoming": upcoming,
+                    "history": self.history,
+                }))
+            }
+        }
+    }
+    //指定编号时注销该预约，未指定时仅在用户只有一个预约时注销
+    fn handle_finish(&mut self, email: &str, id: u64, uid: Option<u32>) -> Reply {
+        let id = match self.find_target(email, id, "finish", uid) {
+            Ok(id) => id,
+            Err(reply) => return reply,
+        };
+        if let Some(x) = self.reservations.get_mut(&id) {
+            x.finish = true;
+        }
+        let body = format! {"用户{}的预约{}注销成功！欢迎下次预约！",email,id};
+        self.send_email(email.to_string(), "任务注销通知", &body);
+        Reply::ok(body).with_id(id)
+    }
+    //操作的目标预约，失败时返回错误应答
+    fn find_target(
+        &self,
+        email: &str,
+        id: u64,
+        command: &str,
+        uid: Option<u32>,
+    ) -> Result<u64, Reply> {
+        self.find_target_by(email, id, command, uid, |_| true)
+    }
+    //只在满足filter的预约中查找，如领取时只考虑待领取的预约
+    fn find_target_by(
+        &self,
+        email: &str,
+        id: u64,
+        command: &str,
+        uid: Option<u32>,
+        filter: impl Fn(&UserWrapper) -> bool,
+    ) -> Result<u64, Reply> {
+        let ids: Vec<u64> = self
+            .reservations
+            .values()
+            .filter(|x| x.email == email && !x.finish && filter(x))
+            .filter(|x| id == 0 || x.id == id)
+            .map(|x| x.id)
+            .collect();
+        if ids.len() == 1 {
+            self.check_owner(&self.reservations[&ids[0]], uid)?;
+            return Ok(ids[0]);
+        }
+        if id != 0 || ids.is_empty() {
+            return Err(Reply::error(
+                ErrorCode::NotFound,
+                format! {"用户{}没有对应的预约！",email},
+            ));
+        }
+        let ids: Vec<String> = ids.iter().map(|id| id.to_string()).collect();
+        Err(Reply::error(
+            ErrorCode::Ambiguous,
+            format! {"用户{}有多个预约({})，请指定预约编号: RustTip {} {} 编号",
+            email,ids.join(", "),command,email},
+        ))
+    }
+    //经本地套接字提交的预约只能由同一uid或管理员uid操作；无uid的请求已在check_identity中按令牌绑定邮箱
+    fn check_owner(&self, user: &UserWrapper, uid: Option<u32>) -> Result<(), Reply> {
+        match (user.uid, uid) {
+            (Some(owner), Some(uid))
+                if owner != uid && !self.settings.admin_uids.contains(&uid) =>
+            {
+                Err(Reply::error(
+                    ErrorCode::Forbidden,
+                    format! {"预约{}由其他本地用户提交，无权操作！",user.id},
+                ))
+            }
+            _ => Ok(()),
+        }
+    }
+    //延长、缩短或改期，原排队时间戳不变
+    fn handle_modify(
+        &mut self,
+        email: &str,
+        id: u64,
+        duration: Option<i64>,
+        start: Option<String>,
+        devices: usize,
+        now: NaiveDateTime,
+    ) -> Reply {
+        let result = self.find_target(email, id, "modify", None).and_then(|id| {
+            let old = &self.reservations[&id];
+            let mut new = old.clone();
+            if duration.is_some() {
+                new.duration = duration;
+            }
+            if let Some(start) = &start {
+                //调度循环按固定格式解析开始时刻，格式错误的请求在此拒绝
+                let start =
+                    NaiveDateTime::parse_from_str(start, "%Y-%m-%d %H:%M:%S").map_err(|_| {
+                        Reply::error(ErrorCode::BadRequest, String::from("开始时刻格式错误！"))
+                    })?;
+                new.date_time = start.to_string();
+            }
+            self.check_modify(old, &new, devices, now)?;
+            Ok(new)
+        });
+        let mut user = match result {
+            Ok(user) => user,
+            Err(reply) => return reply,
+        };
+        user.expiry_notified = false;
+        user.deadline_warned = false;
+        self.reservations.insert(user.id, user.clone());
+        for holder in self.curr_users.values_mut() {
+            if holder.id == user.id {
+                *holder = user.clone();
+            }
+        }
+        let mut body = format! {"用户{}的预约{}已修改为{}！",email,user.id,user.describe()};
+        if let Some(end_time) = user.end_time() {
+            body.push_str(&format! {"预约将于{}到期自动释放。",end_time});
+        }
+        self.send_email(email.to_string(), "预约修改通知", &body);
+        Reply::ok(body).with_id(user.id)
+    }
+    fn check_modify(
+        &self,
+        old: &UserWrapper,
+        new: &UserWrapper,
+        devices: usize,
+        now: NaiveDateTime,
+    ) -> Result<(), Reply> {
+        let rejected = |message: String| Err(Reply::error(ErrorCode::Rejected, message));
+        let held = self.is_holding(old.id);
+        if held && new.date_time != old.date_time {
+            return rejected(format! {"用户{}的预约{}已在使用中，无法改期！",new.email,new.id});
+        }
+        //已在使用的预约不能缩短到当前时刻之前
+        if let Some(end_time) = new.end_time() {
+            if held && end_time <= now {
+                return rejected(
+                    format! {"用户{}的预约{}修改后将立即到期，请直接注销！",new.email,new.id},
+                );
+            }
+        }
+        let extended = matches!((old.duration, new.duration), (Some(old), Some(new)) if new > old);
+        if held && extended && self.get_new_user(now, devices).is_some() {
+            return rejected(format! {"有用户正在排队，无法延长预约{}！",new.id});
+        }
+        if held && extended && self.overlaps_slots(new, new.grant_time().unwrap_or(now), devices) {
+            return rejected(format! {"延长后的预约{}与已预订的时段冲突，无法延长！",new.id});
+        }
+        if self.need_approval(new) && !self.need_approval(old) {
+            return rejected(format! {"修改后的预约{}需要管理员审批，请重新预约！",new.id});
+        }
+        if let Some(body) = self.check_conflict(new, devices) {
+            return Err(Reply::error(ErrorCode::Conflict, body));
+        }
+        Ok(())
+    }
+    //未配置管理员时不启用审批；edf策略下截止时刻决定排队顺序，也需审批
+    fn need_approval(&self, user: &UserWrapper) -> bool {
+        if self.settings.admins.is_empty() || user.finish {
+            return false;
+        }
+        let too_long = user
+            .duration
+            .is_some_and(|duration| duration > self.settings.approval_max_minutes);
+        let deadline = self.settings.policy == config::Policy::Edf && user.deadline.is_some();
+        user.urg || too_long || deadline
+    }
+    //调用方已通过authorize鉴权，admin仍须在管理员邮箱列表中，用于署名通知
+    fn handle_approval(&mut self, admin: &str, id: u64, approve: bool) -> Reply {
+        if !self.settings.admins.iter().any(|x| x == admin) {
+            return Reply::error(
+                ErrorCode::Forbidden,
+                format! {"用户{}不是管理员，无权审批！",admin},
+            );
+        }
+        let user = match self.reservations.get_mut(&id) {
+            Some(user) if user.pending => user,
+            _ => return Reply::error(ErrorCode::NotFound, format! {"预约{}不存在或无需审批！",id}),
+        };
+        user.pending = false;
+        let target = user.email.clone();
+        if approve {
+            self.send_email(
+                target.clone(),
+                "预约审批通过",
+                &format! {"用户{}的预约{}已由管理员{}批准，服务器就绪后将自动通知您！",target,id,admin},
+            );
+            Reply::ok(format! {"已批准用户{}的预约{}！",target,id}).with_id(id)
+        } else {
+            user.finish = true;
+            self.send_email(
+                target.clone(),
+                "预约审批未通过",
+                &format! {"用户{}的预约{}已被管理员{}拒绝！",target,id,admin},
+            );
+            Reply::ok(format! {"已拒绝用户{}的预约{}！",target,id}).with_id(id)
+        }
+    }
+
+    //展开周期预约，任一次冲突则整体拒绝
+    fn handle_repeat(
+        &mut self,
+        user: UserWrapper,
+        rule: Recurrence,
+        until: &str,
+        devices: usize,
+    ) -> Reply {
+        let until = match NaiveDate::parse_from_str(until, "%Y-%m-%d") {
+            Ok(until) => until,
+            Err(_) => {
+                return Reply::error(ErrorCode::BadRequest, String::from("截止日期格式错误！"))
+            }
+        };
+        let duration = user.duration.unwrap_or(0);
+        let dates = rule.expand(user.date_time(), until);
+        if dates.is_empty() {
+            return Reply::error(
+                ErrorCode::BadRequest,
+                format! {"用户{}的周期预约在{}之前没有符合规则的日期！",user.email,until},
             );
         }
+        //相邻两次的间隔短于时长时，同一周期预约的各次会相互重叠
+        if let Some(gap) = dates.windows(2).map(|pair| pair[1] - pair[0]).min() {
+            if Duration::minutes(duration) > gap {
+                return Reply::error(
+                    ErrorCode::BadRequest,
+                    format! {"周期预约每次{}分钟，超过相邻两次的间隔{}分钟！",duration,gap.num_minutes()},
+                );
+            }
+        }
+        for date_time in dates.iter() {
+            let mut occurrence = user.clone();
+            occurrence.date_time = date_time.to_string();
+            occurrence.slot = true;
+            if let Some(body) = self.check_conflict(&occurrence, devices) {
+                return Reply::error(
+                    ErrorCode::Conflict,
+                    format! {"周期预约中{}的一次冲突，周期预约未成功！{}",date_time,body},
+                );
+            }
+        }
+        self.next_series_id += 1;
+        let id = self.next_series_id;
+        self.series.insert(
+            id,
+            Series {
+                email: user.email.clone(),
+                rule: rule.clone(),
+                duration,
+                until: until.to_string(),
+            },
+        );
+        //各次时长相同，与普通预约一样按时长判断是否需要审批
+        let pending = self.need_approval(&user);
+        let mut ids: Vec<String> = Vec::new();
+        for date_time in dates.iter() {
+            let mut occurrence = user.clone();
+            occurrence.date_time = date_time.to_string();
+            occurrence.slot = true;
+            occurrence.series = Some(id);
+            occurrence.pending = pending;
+            ids.push(self.add_reservation(occurrence).to_string());
+        }
+        let mut body = format! {"用户{}的周期预约{}创建成功：{}{}开始，每次{}分钟，至{}共{}次。\n取消单次: RustTip cancel {} {} --date 日期\n取消全部: RustTip cancel {} {}",
+        user.email,id,rule,user.date_time().time(),duration,until,dates.len(),
+        user.email,id,user.email,id};
+        if pending {
+            body.push_str("\n各次预约需要管理员审批，审批结果将邮件通知您！");
+            for admin in self.settings.admins.clone() {
+                self.send_email(
+                    admin.clone(),
+                    "预约待审批",
+                    &format! {"用户{}提交了周期预约{}，每次{}分钟，请逐一审批预约{}：\n同意: RustTip approve {} 预约编号\n拒绝: RustTip reject {} 预约编号",
+                    user.email,id,duration,ids.join("、"),admin,admin},
+                );
+            }
+        }
+        self.send_email(user.email.clone(), "周期预约通知", &body);
+        Reply::ok(body)
+    }
+    fn handle_cancel(
+        &mut self,
+        email: &str,
+        id: u64,
+        date: Option<String>,
+        uid: Option<u32>,
+    ) -> Reply {
+        match self.series.get(&id) {
+            Some(series) if series.email == email => {}
+            _ => {
+                return Reply::error(
+                    ErrorCode::NotFound,
+                    format! {"用户{}没有编号为{}的周期预约！",email,id},
+                )
+            }
+        };
+        let owned = self
+            .reservations
+            .values()
+            .filter(|occurrence| occurrence.series == Some(id))
+            .try_for_each(|occurrence| self.check_owner(occurrence, uid));
+        if let Err(reply) = owned {
+            return reply;
+        }
+        let mut cancelled: Vec<u64> = Vec::new();
+        for occurrence in self.reservations.values_mut() {
+            let same_day = match &date {
+                Some(date) => occurrence.date_time.starts_with(date.as_str()),
+                None => true,
+            };
+            if occurrence.series == Some(id) && same_day && !occurrence.finish {
+                occurrence.finish = true;
+                cancelled.push(occurrence.id);
+            }
+        }
+        if date.is_none() {
+            self.series.remove(&id);
+        }
+        let body = match date {
+            Some(date) if cancelled.is_empty() => {
+                return Reply::error(
+                    ErrorCode::NotFound,
+                    format! {"周期预约{}在{}没有可取消的预约！",id,date},
+                )
+            }
+            Some(date) => format! {"周期预约{}在{}的预约已取消！",id,date},
+            None => format! {"周期预约{}已全部取消！",id},
+        };
+        self.send_email(email.to_string(), "周期预约取消通知", &body);
+        Reply::ok(body)
+    }
+    fn update_current_user(&mut self, gpus: &[nvidia::Nvidia], now: NaiveDateTime) {
+        //同步map内容到各卡持有者，已不在map中的视为注销
+        for holder in self.curr_users.values_mut() {
+            match self.reservations.get(&holder.id) {
+                Some(user) => *holder = user.clone(),
+                None => holder.finish = true,
+            }
+        }
+        //释放的显卡计入持有者的历史用量
+        let released: Vec<UserWrapper> = self
+            .curr_users
+            .values()
+            .filter(|holder| holder.finish)
+            .cloned()
+            .collect();
+        for holder in released {
+            if let Some(grant_time) = holder.grant_time() {
+                let hours = (now - grant_time).num_seconds() as f64 / 3600.0;
+                self.record_usage(&holder.email, hours, now);
+            }
+        }
         //清除map中所有finish的对象
-        let temp_users = self.user_info.clone();
-        for user in temp_users.iter() {
-            if user.1.finish {
-                self.user_info.remove(user.0);
+        self.reservations.retain(|_, user| !user.finish);
+        self.curr_users.retain(|_, holder| !holder.finish);
+        //周期预约的各次全部结束后不再保留
+        let reservations = &self.reservations;
+        self.series
+            .retain(|id, _| reservations.values().any(|x| x.series == Some(*id)));
+        self.reclaim_for_slots(now, gpus.len());
+        //为每张空闲的卡分配下一位用户
+        for gpu in gpus {
+            if self.curr_users.contains_key(&gpu.index()) {
+                continue;
+            }
+            if let Some(mut user) = self.get_new_user(now, gpus.len()) {
+                //启用领取确认时先为其保留显卡，确认后才开始计时
+                let claim = self.settings.claim_minutes > 0;
+                if claim {
+                    user.offered_at = Some(now.to_string());
+                } else {
+                    user.grant_time = Some(now.to_string());
+                }
+                user.requeued = false;
+                user.order = None;
+                if let Some(x) = self.reservations.get_mut(&user.id) {
+                    x.grant_time = user.grant_time.clone();
+                    x.offered_at = user.offered_at.clone();
+                    x.requeued = false;
+                    x.order = None;
+                }
+                if claim {
+                    let body = format! {"用户{}的预约{}已轮到显卡{}，请在{}分钟内确认领取：RustTip claim {} {}\n逾期未领取将排到队尾！",
+                    user.email,user.id,gpu.index(),self.settings.claim_minutes,user.email,user.id};
+                    self.send_email(user.email.clone(), "设备轮到通知", &body);
+                } else {
+                    self.notify_grant(&user, gpu.index());
+                }
+                self.curr_users.insert(gpu.index(), user);
+                //重置诊断计时
+                TIME_GAP
+                    .lock()
+                    .unwrap()
+                    .insert(gpu.index(), Duration::seconds(config::TIME_GAP_SECONDS));
+                IDLE_WARNED.lock().unwrap().remove(&gpu.index());
             }
         }
-        //更新curr_user
-        if self.curr_user.is_none() || self.curr_user.as_ref().unwrap().finish {
-            self.curr_user = self.get_new_user();
-            //重置诊断计时
-            let mut gap = TIME_GAP.lock().unwrap();
-            *gap = Duration::seconds(config::TIME_GAP_SECONDS);
+    }
+    fn notify_grant(&self, user: &UserWrapper, index: u32) {
+        let mut body = format! {"用户{}的预约{}已分配到显卡{}，请尽快使用！",
+        user.email,user.id,index};
+        if let Some(end_time) = user.end_time() {
+            body.push_str(&format! {"预约将于{}到期自动释放。",end_time});
         }
+        self.send_email(user.email.clone(), "设备分配通知", &body);
     }
-    fn get_new_user(&self) -> Option<UserWrapper> {
-        //基于urg、时间戳比较
-        let mut users: Vec<(String, User)> = Vec::new();
-        for info in self.user_info.clone() {
-            users.push((info.0, info.1.into()));
+    //领取轮到的显卡，从此刻开始计时
+    fn handle_claim(
+        &mut self,
+        email: &str,
+        id: u64,
+        uid: Option<u32>,
+        now: NaiveDateTime,
+    ) -> Reply {
+        //有多个待领取的预约时需要指定编号
+        let offered = match self.find_target_by(email, id, "claim", uid, |x| x.offered_at.is_some())
+        {
+            Ok(id) => self.reservations.get_mut(&id),
+            Err(reply)
+                if matches!(
+                    reply.code,
+                    Some(ErrorCode::Ambiguous) | Some(ErrorCode::Forbidden)
+                ) =>
+            {
+                return reply
+            }
+            Err(_) => None,
+        };
+        let user = match offered {
+            Some(user) => {
+                user.offered_at = None;
+                user.grant_time = Some(now.to_string());
+                user.no_shows = 0;
+                user.clone()
+            }
+            None => {
+                return Reply::error(
+                    ErrorCode::NotFound,
+                    format! {"用户{}没有待领取的显卡！",email},
+                )
+            }
+        };
+        let index = self
+            .curr_users
+            .iter()
+            .find(|(_, holder)| holder.id == user.id)
+            .map(|(index, _)| *index);
+        if let Some(index) = index {
+            self.curr_users.insert(index, user.clone());
+            self.notify_grant(&user, index);
         }
-
-        users.sort_by(|a, b| {
-            if a.1.urg > b.1.urg {
-                return Ordering::Greater;
-            } else if a.1.urg < b.1.urg {
-                return Ordering::Less;
+        Reply::ok(format! {"用户{}的预约{}领取成功！",email,user.id}).with_id(user.id)
+    }
+    //超过领取时限未确认的预约排到队尾，显卡转给下一位
+    fn check_claims(&mut self, now: NaiveDateTime) {
+        let window = Duration::minutes(self.settings.claim_minutes);
+        let expired: Vec<u64> = self
+            .curr_users
+            .values()
+            .filter(|holder| {
+                holder
+                    .offered_at()
+                    .is_some_and(|offered| now - offered >= window)
+            })
+            .map(|holder| holder.id)
+            .collect();
+        for id in expired {
+            self.curr_users.retain(|_, holder| holder.id != id);
+            let user = match self.reservations.get_mut(&id) {
+                Some(user) => user,
+                None => continue,
+            };
+            user.offered_at = None;
+            user.no_shows += 1;
+            let body = if user.no_shows >= config::MAX_NO_SHOWS {
+                user.finish = true;
+                format! {"用户{}的预约{}连续{}次未按时领取，预约已注销！",user.email,id,user.no_shows}
             } else {
-                if a.1.timestamp < b.1.timestamp {
-                    return Ordering::Greater;
-                } else if a.1.timestamp > b.1.timestamp {
-                    return Ordering::Less;
-                } else {
-                    return Ordering::Equal;
+                format! {"用户{}的预约{}未在{}分钟内领取，已排到队尾，显卡已转给下一位！",
+                user.email,id,self.settings.claim_minutes}
+            };
+            let email = user.email.clone();
+            self.send_email(email, "领取超时通知", &body);
+        }
+    }
+    fn get_new_user(&self, now: NaiveDateTime, devices: usize) -> Option<UserWrapper> {
+        //时段预约优先，其次基于urg、时间戳比较，已分配显卡的预约不参与
+        let lookahead = Duration::minutes(self.settings.lookahead_minutes);
+        let mut users: Vec<&UserWrapper> = Vec::new();
+        for info in self.reservations.values() {
+            if info.pending {
+                continue;
+            }
+            if let Some((start, end)) = info.slot() {
+                if now < start || now >= end {
+                    continue;
                 }
             }
-        });
+            //未到开始时刻的用户不提前分配，除非允许提前且处于提前窗口内
+            let wait = info.date_time() - now;
+            if wait > Duration::zero() && !(info.early && wait <= lookahead) {
+                continue;
+            }
+            //预计使用期间会占用已订满时段的显卡时不分配
+            if !info.slot && self.overlaps_slots(info, now, devices) {
+                continue;
+            }
+            if !self.is_holding(info.id) {
+                users.push(info);
+            }
+        }
+
+        users.sort_by(|a, b| self.compare(a, b, now));
         //取最大的点
-        if users.len() == 0 {
-            None
-        } else if users.len() == 1 {
-            Some(UserWrapper::from(users[0].1.clone()))
-        } else {
-            let now: NaiveDateTime = NaiveDateTimeWrapper::from(Local::now()).into();
-            let mut ret = Some(UserWrapper::from(users[users.len() - 1].1.clone()));
-            if users[users.len() - 1].1.date_time - now <= Duration::hours(10) {
-                ret
+        users.last().map(|user| (*user).clone())
+    }
+    //非时段预约从start起按预计时长使用，其余非时段持有者保守地视为一直占用
+    fn overlaps_slots(&self, user: &UserWrapper, start: NaiveDateTime, devices: usize) -> bool {
+        let duration = match user.duration {
+            Some(duration) => duration,
+            None => return false,
+        };
+        let others = self
+            .curr_users
+            .values()
+            .filter(|holder| !holder.slot && holder.id != user.id)
+            .count();
+        let mut probe = user.clone();
+        probe.slot = true;
+        probe.duration = Some(duration);
+        probe.date_time = start.to_string();
+        self.find_conflict(&probe, devices.saturating_sub(others))
+            .is_some()
+    }
+    //时段开始时显卡仍被占用(未申报时长的持有者)，收回最晚分配的非时段持有者并使其优先重新排队
+    fn reclaim_for_slots(&mut self, now: NaiveDateTime, devices: usize) {
+        let waiting = self
+            .reservations
+            .values()
+            .filter(|user| !user.pending && !user.finish && !self.is_holding(user.id))
+            .filter(|user| {
+                user.slot()
+                    .is_some_and(|(start, end)| start <= now && now < end)
+            })
+            .count();
+        let needed = waiting.saturating_sub(devices.saturating_sub(self.curr_users.len()));
+        for _ in 0..needed {
+            //待领取的尚未开始使用，优先收回
+            let victim = self
+                .curr_users
+                .iter()
+                .filter(|(_, holder)| !holder.slot)
+                .max_by_key(|(_, holder)| (holder.grant_time().is_none(), holder.grant_time()))
+                .map(|(index, _)| *index);
+            let index = match victim {
+                Some(index) => index,
+                None => break,
+            };
+            let holder = self.curr_users.remove(&index).unwrap();
+            if let Some(grant_time) = holder.grant_time() {
+                let hours = (now - grant_time).num_seconds() as f64 / 3600.0;
+                self.record_usage(&holder.email, hours, now);
+            }
+            if let Some(x) = self.reservations.get_mut(&holder.id) {
+                x.grant_time = None;
+                x.offered_at = None;
+                x.preempt_at = None;
+                x.expiry_notified = false;
+                x.requeued = true;
+            }
+            self.send_email(
+                holder.email.clone(),
+                "显卡收回通知",
+                &format! {"用户{}的显卡{}已到预订时段的开始时刻，显卡已收回，您已优先重新排队！",holder.email,index},
+            );
+        }
+    }
+    fn compare(&self, a: &UserWrapper, b: &UserWrapper, now: NaiveDateTime) -> Ordering {
+        b.no_shows
+            .cmp(&a.no_shows)
+            .then(a.slot.cmp(&b.slot))
+            .then(a.order.map(Reverse).cmp(&b.order.map(Reverse)))
+            .then(self.compare_slack(a, b, now))
+            .then(a.urg.cmp(&b.urg))
+            .then(a.requeued.cmp(&b.requeued))
+            .then(
+                self.priority(a, now)
+                    .partial_cmp(&self.priority(b, now))
+                    .unwrap_or(Ordering::Equal),
+            )
+            .then(b.timestamp.cmp(&a.timestamp))
+            .then(b.id.cmp(&a.id))
+    }
+
+    //edf策略下可按时完成的预约优先，松弛时间越小越优先；已无法按时完成的按普通预约排队
+    fn compare_slack(&self, a: &UserWrapper, b: &UserWrapper, now: NaiveDateTime) -> Ordering {
+        if self.settings.policy != config::Policy::Edf {
+            return Ordering::Equal;
+        }
+        let feasible =
+            |user: &UserWrapper| user.slack(now).filter(|slack| *slack >= Duration::zero());
+        match (feasible(a), feasible(b)) {
+            (Some(x), Some(y)) => y.cmp(&x),
+            (Some(_), None) => Ordering::Greater,
+            (None, Some(_)) => Ordering::Less,
+            (None, None) => Ordering::Equal,
+        }
+    }
+
+    //按预计开始时刻检查截止时刻，无法按时完成时提醒一次
+    fn check_deadlines(&mut self) {
+        let mut warnings: Vec<(u64, String, String)> = Vec::new();
+        for estimate in self.queue.iter() {
+            let user = match self.reservations.get(&estimate.id) {
+                Some(user) if !user.deadline_warned => user,
+                _ => continue,
+            };
+            let (deadline, eta) = match (user.deadline(), estimate.eta.as_ref()) {
+                (Some(deadline), Some(eta)) => (deadline, eta),
+                _ => continue,
+            };
+            let eta = match NaiveDateTime::parse_from_str(eta, "%Y-%m-%d %H:%M:%S") {
+                Ok(eta) => eta,
+                Err(_) => continue,
+            };
+            let finish = eta + Duration::minutes(user.duration.unwrap_or(0));
+            if finish > deadline {
+                warnings.push((
+                    user.id,
+                    user.email.clone(),
+                    format! {
+                        "用户{}的预约{}预计{}开始、{}完成，晚于截止时刻{}，请调整计划！",
+                        user.email,user.id,eta,finish,deadline
+                    },
+                ));
+            }
+        }
+        for (id, email, body) in warnings {
+            if let Some(x) = self.reservations.get_mut(&id) {
+                x.deadline_warned = true;
+            }
+            self.send_email(email, "截止时刻预警", &body);
+        }
+    }
+
+    //按分配顺序依次占用最早空出的显卡，估计每位排队用户的开始时刻
+    //尚未开始的时段预约不参与排队，预计开始时刻即时段开始
+    fn estimate_queue(&self, now: NaiveDateTime, devices: usize) -> Vec<Estimate> {
+        let waiting = self.waiting(now);
+        let booked = self.upcoming(now);
+
+        //各显卡预计空出时刻，None表示无法估计
+        let mut free_at: Vec<Option<NaiveDateTime>> = self
+            .curr_users
+            .values()
+            .map(|holder| {
+                let end_time = holder.end_time().or_else(|| {
+                    Some(holder.grant_time()? + self.expected_duration(&holder.email)?)
+                })?;
+                Some(end_time.max(now))
+            })
+            .collect();
+        free_at.resize(devices.max(self.curr_users.len()), Some(now));
+
+        let mut estimates: Vec<Estimate> = Vec::new();
+        for (i, user) in waiting.iter().enumerate() {
+            let device = free_at
+                .iter()
+                .enumerate()
+                .min_by_key(|(_, time)| time.unwrap_or(NaiveDateTime::MAX))
+                .map(|(index, _)| index);
+            let eta = device.and_then(|device| {
+                let eta = free_at[device]?.max(user.date_time());
+                free_at[device] = user
+                    .duration
+                    .map(Duration::minutes)
+                    .or_else(|| self.expected_duration(&user.email))
+                    .map(|duration| eta + duration);
+                Some(eta)
+            });
+            estimates.push(Estimate {
+                id: user.id,
+                email: user.email.clone(),
+                position: i + 1,
+                eta: eta.map(|eta| eta.to_string()),
+            });
+        }
+        for user in booked {
+            estimates.push(Estimate {
+                id: user.id,
+                email: user.email.clone(),
+                position: estimates.len() + 1,
+                eta: Some(user.date_time.clone()),
+            });
+        }
+        estimates
+    }
+    //排队中的预约，按分配顺序排列，不含尚未开始的时段预约
+    fn waiting(&self, now: NaiveDateTime) -> Vec<&UserWrapper> {
+        let mut waiting: Vec<&UserWrapper> = self
+            .reservations
+            .values()
+            .filter(|user| !user.pending && !user.finish && !self.is_holding(user.id))
+            .filter(|user| user.slot().is_none_or(|(start, _)| start <= now))
+            .collect();
+        waiting.sort_by(|a, b| self.compare(b, a, now));
+        waiting
+    }
+    //尚未开始的时段预约，按开始时刻排列
+    fn upcoming(&self, now: NaiveDateTime) -> Vec<&UserWrapper> {
+        let mut booked: Vec<&UserWrapper> = self
+            .reservations
+            .values()
+            .filter(|user| !user.pending && !user.finish && !self.is_holding(user.id))
+            .filter(|user| user.slot().is_some_and(|(start, _)| start > now))
+            .collect();
+        booked.sort_by_key(|user| user.date_time());
+        booked
+    }
+    //按采样间隔记录读数，只保留最近HISTORY_SAMPLES个
+    fn record_history(&mut self, now: NaiveDateTime) {
+        let due = self.history.back().is_none_or(|last| {
+            NaiveDateTime::parse_from_str(&last.time, "%Y-%m-%d %H:%M:%S").map_or(true, |last| {
+                now - last >= Duration::seconds(config::HISTORY_STEP_SECONDS)
+            })
+        });
+        if !due {
+            return;
+        }
+        self.history.push_back(Sample {
+            time: now.to_string(),
+            readings: self.readings.clone(),
+        });
+        let lines: Vec<String> = self
+            .readings
+            .iter()
+            .map(|x| format! {"显卡{}: {}% {}/{}MB",x.index,x.use_ratio,x.used_memory,x.total_memory})
+            .collect();
+        self.events.push(
+            Event::new(EventKind::Metrics, now.to_string(), lines.join("，"))
+                .with_data(serde_json::json!({ "readings": self.readings })),
+        );
+        while self.history.len() > config::HISTORY_SAMPLES {
+            self.history.pop_front();
+        }
+    }
+    //对比本轮开始时的持有者，生成释放、轮到与分配事件
+    fn emit_holder_changes(&mut self, before: &BTreeMap<u32, UserWrapper>, now: NaiveDateTime) {
+        let time = now.to_string();
+        for (index, old) in before.iter() {
+            if self
+                .curr_users
+                .get(index)
+                .is_none_or(|new| new.id != old.id)
+            {
+                self.events.push(
+                    Event::new(
+                        EventKind::Released,
+                        time.clone(),
+                        format! {"用户{}的预约{}释放了显卡{}",old.email,old.id,index},
+                    )
+                    .with_user(&old.email, old.id),
+                );
+            }
+        }
+        for (index, new) in self.curr_users.iter() {
+            let old = before.get(index).filter(|old| old.id == new.id);
+            let (kind, message) = if new.offered_at.is_some() {
+                if old.is_some() {
+                    continue;
+                }
+                (
+                    EventKind::Offered,
+                    format! {"用户{}的预约{}轮到显卡{}，等待领取",new.email,new.id,index},
+                )
             } else {
-                for i in (0..users.len() - 1).rev() {
-                    if users[i].1.date_time - now <= Duration::hours(10) {
-                        ret = Some(UserWrapper::from(users[i].1.clone()));
-                        break;
-                    }
+                if old.is_some_and(|old| old.offered_at.is_none()) {
+                    continue;
                 }
-                ret
+                (
+                    EventKind::Granted,
+                    format! {"用户{}的预约{}开始使用显卡{}",new.email,new.id,index},
+                )
+            };
+            self.events
+                .push(Event::new(kind, time.clone(), message).with_user(&new.email, new.id));
+        }
+    }
+    //用户历史平均持有时长，没有记录时使用所有用户的平均值
+    fn expected_duration(&self, email: &str) -> Option<Duration> {
+        let (holds, minutes) = match self.usage.get(email) {
+            Some(usage) if usage.holds > 0 => (usage.holds, usage.hold_minutes),
+            _ => self.usage.values().fold((0, 0), |(holds, minutes), usage| {
+                (holds + usage.holds, minutes + usage.hold_minutes)
+            }),
+        };
+        if holds == 0 {
+            return None;
+        }
+        Some(Duration::minutes(minutes / holds as i64))
+    }
+
+    fn is_holding(&self, id: u64) -> bool {
+        self.curr_users.values().any(|holder| holder.id == id)
+    }
+
+    //有紧急预约等待且没有空闲显卡时，提前警告持有者，宽限期后收回显卡
+    fn check_preemption(&mut self, now: NaiveDateTime, devices: usize) {
+        if !self.settings.preempt {
+            return;
+        }
+        let waiting = self
+            .reservations
+            .values()
+            .filter(|user| user.urg && !user.pending && !user.finish)
+            .filter(|user| !self.is_holding(user.id))
+            .count();
+        let needed = waiting.saturating_sub(devices.saturating_sub(self.curr_users.len()));
+        let mut marked: Vec<u64> = self
+            .curr_users
+            .values()
+            .filter(|holder| holder.preempt_at.is_some())
+            .map(|holder| holder.id)
+            .collect();
+
+        //紧急预约已得到满足，撤销多余的抢占
+        while marked.len() > needed {
+            let id = marked.pop().unwrap();
+            if let Some(x) = self.reservations.get_mut(&id) {
+                x.preempt_at = None;
+                let email = x.email.clone();
+                self.send_email(
+                    email.clone(),
+                    "抢占取消通知",
+                    &format! {"用户{}的预约{}无需收回显卡，请继续使用！",email,id},
+                );
             }
         }
+        //选择最早分配的普通持有者，时段预约与紧急预约不被抢占
+        while marked.len() < needed {
+            let victim = self
+                .curr_users
+                .values()
+                .filter(|holder| !holder.urg && !holder.slot && holder.preempt_at.is_none())
+                .filter(|holder| !marked.contains(&holder.id))
+                .min_by_key(|holder| holder.grant_time())
+                .map(|holder| (holder.id, holder.email.clone()));
+            let (id, email) = match victim {
+                Some(victim) => victim,
+                None => break,
+            };
+            let preempt_at = now + Duration::minutes(self.settings.preempt_grace_minutes);
+            if let Some(x) = self.reservations.get_mut(&id) {
+                x.preempt_at = Some(preempt_at.to_string());
+            }
+            self.send_email(
+                email.clone(),
+                "显卡抢占警告",
+                &format! {"有紧急预约等待，用户{}的显卡将在{}分钟后({})收回，请及时保存数据！收回后您将优先重新排队。",
+                email,self.settings.preempt_grace_minutes,preempt_at},
+            );
+            marked.push(id);
+        }
+        //宽限期已过，收回显卡并重新排队
+        let expired: Vec<u32> = self
+            .curr_users
+            .iter()
+            .filter_map(|(index, holder)| {
+                let preempt_at = self.reservations.get(&holder.id)?.preempt_at.as_ref()?;
+                let preempt_at =
+                    NaiveDateTime::parse_from_str(preempt_at, "%Y-%m-%d %H:%M:%S").ok()?;
+                (now >= preempt_at).then_some(*index)
+            })
+            .collect();
+        for index in expired {
+            let holder = self.curr_users.remove(&index).unwrap();
+            if let Some(grant_time) = holder.grant_time() {
+                let hours = (now - grant_time).num_seconds() as f64 / 3600.0;
+                self.record_usage(&holder.email, hours, now);
+            }
+            if let Some(x) = self.reservations.get_mut(&holder.id) {
+                x.grant_time = None;
+                x.preempt_at = None;
+                x.expiry_notified = false;
+                x.requeued = true;
+            }
+            self.send_email(
+                holder.email.clone(),
+                "显卡收回通知",
+                &format! {"用户{}的显卡{}已被紧急预约收回，您已优先重新排队！",holder.email,index},
+            );
+        }
     }
 
-    fn send_email(&self, mut receiver: String, subject: &str, body: &String) {
-        let creds = Credentials::new(
-            self.server_info.account.clone(),
-            self.server_info.password.clone(),
+    //公平份额：等待时长(小时)减去加权的衰减用量(GPU小时)
+    fn priority(&self, user: &UserWrapper, now: NaiveDateTime) -> f64 {
+        let now_timestamp = Local
+            .from_local_datetime(&now)
+            .earliest()
+            .map_or(user.timestamp, |now| now.timestamp());
+        let wait_hours = (now_timestamp - user.timestamp) as f64 / 3600.0;
+        wait_hours - self.settings.fair_share_weight * self.decayed_usage(&user.email, now)
+    }
+    fn decayed_usage(&self, email: &str, now: NaiveDateTime) -> f64 {
+        let usage = match self.usage.get(email) {
+            Some(usage) => usage,
+            None => return 0.0,
+        };
+        let updated =
+            NaiveDateTime::parse_from_str(&usage.updated, "%Y-%m-%d %H:%M:%S").unwrap_or(now);
+        let elapsed_hours = (now - updated).num_seconds().max(0) as f64 / 3600.0;
+        usage.gpu_hours * 0.5f64.powf(elapsed_hours / self.settings.half_life_hours)
+    }
+    fn record_usage(&mut self, email: &str, hours: f64, now: NaiveDateTime) {
+        let gpu_hours = self.decayed_usage(email, now) + hours.max(0.0);
+        let (holds, hold_minutes) = self
+            .usage
+            .get(email)
+            .map_or((0, 0), |usage| (usage.holds, usage.hold_minutes));
+        self.usage.insert(
+            email.to_string(),
+            Usage {
+                gpu_hours,
+                updated: now.to_string(),
+                holds: holds + 1,
+                hold_minutes: hold_minutes + (hours.max(0.0) * 60.0) as i64,
+            },
         );
-        let mailer = SmtpTransport::relay(config::SERVER)
-            .unwrap()
-            .credentials(creds)
-            .build();
-        let mut account = self.server_info.account.clone();
-        account.insert_str(0, "<");
-        account.push_str(">");
-        receiver.insert_str(0, "<");
-        receiver.push_str(">");
-        let msg = Message::builder()
-            .from(account.parse().unwrap())
-            .to(receiver.parse().unwrap())
-            .subject(subject)
-            .body(body.clone())
-            .unwrap();
-        while mailer.send(&msg).is_err() {}
     }
-    fn dialog(&self, gpu: &mut nvidia::Nvidia) {
-        let now = Local::now().time();
+
+    //与已有时段预约冲突时返回拒绝说明，包含冲突的预约与最近可用时段
+    fn check_conflict(&self, booking: &UserWrapper, devices: usize) -> Option<String> {
+        let conflict = self.find_conflict(booking, devices)?;
+        let (start, end) = conflict.slot()?;
+        let mut body = format! {"用户{}的预约与用户{}的预约({} ~ {})冲突，预约未成功！",
+        booking.email,conflict.email,start,end};
+        if let Some(free) = self.next_free_window(booking, devices) {
+            let (free_start, free_end) = free.slot()?;
+            body.push_str(&format! {"最近可用时段为{} ~ {}。",free_start,free_end});
+        }
+        Some(body)
+    }
+    //同一时刻的时段预约数不得超过显卡数
+    fn find_conflict(&self, booking: &UserWrapper, devices: usize) -> Option<&UserWrapper> {
+        let (start, end) = booking.slot()?;
+        let others: Vec<(&UserWrapper, NaiveDateTime, NaiveDateTime)> = self
+            .reservations
+            .values()
+            .filter(|other| other.id != booking.id && !other.finish)
+            .filter_map(|other| other.slot().map(|(s, e)| (other, s, e)))
+            .filter(|(_, s, e)| *s < end && start < *e)
+            .collect();
+        //重叠数只会在某个预约开始时增加
+        let mut points = vec![start];
+        points.extend(others.iter().map(|(_, s, _)| *s).filter(|s| *s > start));
+        for point in points {
+            let active: Vec<&UserWrapper> = others
+                .iter()
+                .filter(|(_, s, e)| *s <= point && point < *e)
+                .map(|(other, _, _)| *other)
+                .collect();
+            if !active.is_empty() && active.len() >= devices {
+                return Some(active[0]);
+            }
+        }
+        None
+    }
+    //从请求时刻起，依次尝试各预约结束时刻，找到最近的无冲突时段
+    fn next_free_window(&self, booking: &UserWrapper, devices: usize) -> Option<UserWrapper> {
+        let (start, _) = booking.slot()?;
+        let mut candidates: Vec<NaiveDateTime> = self
+            .reservations
+            .values()
+            .filter(|other| other.id != booking.id && !other.finish)
+            .filter_map(|other| other.slot().map(|(_, e)| e))
+            .filter(|e| *e > start)
+            .collect();
+        candidates.sort();
+        for candidate in candidates {
+            let mut moved = booking.clone();
+            moved.date_time = candidate.to_string();
+            if self.find_conflict(&moved, devices).is_none() {
+                return Some(moved);
+            }
+        }
+        None
+    }
+
+    //到期前提醒一次，到期后标记注销，由update_current_user推进队列
+    fn check_expiry(&mut self, now: NaiveDateTime) {
+        //时段已过仍未分配到显卡的预约直接作废
+        for user in self.reservations.values_mut() {
+            if let Some((_, end)) = user.slot() {
+                if now >= end && self.curr_users.values().all(|h| h.id != user.id) {
+                    user.finish = true;
+                }
+            }
+        }
+        let mut expired: Vec<(u64, String)> = Vec::new();
+        let mut expiring: Vec<(u64, String, NaiveDateTime)> = Vec::new();
+        for holder in self.curr_users.values() {
+            let end_time = match holder.end_time() {
+                Some(end_time) => end_time,
+                None => continue,
+            };
+            if now >= end_time {
+                expired.push((holder.id, holder.email.clone()));
+            } else if !holder.expiry_notified
+                && end_time - now <= Duration::minutes(config::EXPIRY_NOTICE_MINUTES)
+            {
+                expiring.push((holder.id, holder.email.clone(), end_time));
+            }
+        }
+        for (id, email, end_time) in expiring {
+            if let Some(x) = self.reservations.get_mut(&id) {
+                x.expiry_notified = true;
+            }
+            self.send_email(
+                email.clone(),
+                "预约到期提醒",
+                &format! {"用户{}的预约{}将于{}到期，请及时保存数据！",email,id,end_time},
+            );
+        }
+        for (id, email) in expired {
+            if let Some(x) = self.reservations.get_mut(&id) {
+                x.finish = true;
+            }
+            self.send_email(
+                email.clone(),
+                "预约到期通知",
+                &format! {"用户{}的预约{}已到期，显卡已自动释放！",email,id},
+            );
+        }
+    }
+
+    fn send_email(&self, receiver: String, subject: &str, body: &str) {
+        if self.dry_run {
+            return;
+        }
+        let mail = Mail {
+            account: self.server_info.account.clone(),
+            password: self.server_info.password.clone(),
+            receiver,
+            subject: subject.to_string(),
+            body: body.to_string(),
+        };
+        MAILER.lock().unwrap().send(mail).ok();
+    }
+    //强制释放记录只保留最近的MAX_RELEASES条
+    fn record_release(&mut self, release: Release) {
+        self.releases.push(release);
+        let excess = self.releases.len().saturating_sub(config::MAX_RELEASES);
+        self.releases.drain(..excess);
+    }
+    fn dialog(&mut self, gpus: &mut [nvidia::Nvidia], now_date_time: NaiveDateTime) {
+        nvidia::Nvidia::read_from_terminal(gpus);
+        self.diagnose(gpus, now_date_time);
+    }
+    //按最新读数诊断各卡：空闲通知、空闲强制释放与低效率通知
+    fn diagnose(&mut self, gpus: &mut [nvidia::Nvidia], now_date_time: NaiveDateTime) {
+        let now = now_date_time.time();
         let start_time = NaiveTime::parse_from_str("08:00:00", "%H:%M:%S").unwrap();
         let end_time = NaiveTime::parse_from_str("21:30:00", "%H:%M:%S").unwrap();
         let mut last_time = LAST_TIME.lock().unwrap();
-        let mut gap = TIME_GAP.lock().unwrap();
-        let time_diff = if last_time.is_none() || now - last_time.unwrap() > *gap {
-            true
-        } else {
-            false
+        let mut time_gap = TIME_GAP.lock().unwrap();
+        let mut idle_warned = IDLE_WARNED.lock().unwrap();
+        let idle_release = Duration::minutes(self.settings.idle_release_minutes);
+        let mut released: Vec<(u32, u64, String)> = Vec::new();
+        let mut warnings: Vec<Event> = Vec::new();
+
+        //每张卡对照各自的持有者诊断
+        for gpu in gpus.iter_mut() {
+            //尚未领取的显卡不做诊断
+            let holder = match self.curr_users.get(&gpu.index()) {
+                Some(holder) if holder.offered_at.is_none() => holder,
+                _ => continue,
+            };
+            let gap = time_gap
+                .entry(gpu.index())
+                .or_insert_with(|| Duration::seconds(config::TIME_GAP_SECONDS));
+            let time_diff = match last_time.get(&gpu.index()) {
+                Some(last) => now - *last > *gap,
+                None => true,
+            };
+
+            //任务结束通知，每段时间通知一次，指数增长
+            let bound = now >= start_time && now <= end_time && time_diff;
+            if gpu.is_free() && bound {
+                last_time.insert(gpu.index(), now);
+                *gap = (*gap * 2).min(Duration::minutes(config::TIME_GAP_MAX_MINUTES));
+                idle_warned.entry(gpu.index()).or_insert(now_date_time);
+                warnings.push(
+                    Event::new(
+                        EventKind::IdleWarning,
+                        now_date_time.to_string(),
+                        format! {"用户{}的显卡{}空闲",holder.email,gpu.index()},
+                    )
+                    .with_user(&holder.email, holder.id),
+                );
+                self.send_email(
+                    holder.email.clone(),
+                    "设备空闲通知",
+                    &format! {"用户{}显卡{}空闲，请在服务器进行确认！",holder.email,gpu.index()},
+                );
+            }
+            //空闲通知后持续空闲超过设定时长，强制释放
+            if !gpu.is_idle() {
+                idle_warned.remove(&gpu.index());
+            } else if let Some(warned) = idle_warned.get(&gpu.index()) {
+                if self.settings.idle_release_minutes > 0 && now_date_time - *warned >= idle_release
+                {
+                    idle_warned.remove(&gpu.index());
+                    released.push((gpu.index(), holder.id, holder.email.clone()));
+                }
+            }
+            if gpu.is_low_efficiency() && bound {
+                last_time.insert(gpu.index(), now);
+                *gap = (*gap * 2).min(Duration::minutes(config::TIME_GAP_MAX_MINUTES));
+                self.send_email(
+                    holder.email.clone(),
+                    "任务效率通知",
+                    &format! {"用户{}显卡{}运行效率较低，请检查！",holder.email,gpu.index()},
+                );
+            }
+        }
+        self.events.extend(warnings);
+        for (index, id, email) in released {
+            if let Some(x) = self.reservations.get_mut(&id) {
+                x.finish = true;
+            }
+            self.record_release(Release {
+                email: email.clone(),
+                device: index,
+                time: now_date_time.to_string(),
+                reason: String::from("idle"),
+            });
+            self.send_email(
+                email.clone(),
+                "显卡强制释放通知",
+                &format! {"用户{}的显卡{}在空闲通知后持续空闲{}分钟，已被强制释放！",
+                email,index,self.settings.idle_release_minutes},
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn slot_user(email: &str, start: &str, minutes: i64) -> UserWrapper {
+        let start = NaiveDateTime::parse_from_str(start, "%Y-%m-%d %H:%M:%S").unwrap();
+        UserWrapper::from(
+            User::new(
+                email.to_string(),
+                Some(start.date()),
+                Some(start.time()),
+                false,
+            )
+            .with_duration(Some(minutes))
+            .with_slot(true),
+        )
+    }
+
+    #[test]
+    fn test_slot_conflict() {
+        let mut info = AppInfo::new();
+        let first = slot_user("a@a.com", "2030-01-01 14:00:00", 240);
+        info.add_reservation(first);
+
+        let booking = slot_user("b@b.com", "2030-01-01 16:00:00", 120);
+        assert_eq!(info.find_conflict(&booking, 1).unwrap().email, "a@a.com");
+        assert!(info.find_conflict(&booking, 2).is_none());
+        let free = info.next_free_window(&booking, 1).unwrap();
+        assert_eq!(free.date_time, "2030-01-01 18:00:00");
+
+        let later = slot_user("c@c.com", "2030-01-01 18:00:00", 60);
+        assert!(info.find_conflict(&later, 1).is_none());
+    }
+
+    #[test]
+    fn test_duration_overflow_rejected() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        let booking = User::new(
+            String::from("a@a.com"),
+            Some(now.date()),
+            Some(now.time()),
+            false,
+        )
+        .with_duration(Some(i64::MAX / 60));
+        let reply = info.handle(Request::Book(booking.into()), None, 1, now);
+        assert_eq!(reply.code, Some(ErrorCode::BadRequest));
+        assert!(info.reservations.is_empty());
+    }
+
+    #[test]
+    fn test_slot_blocks_and_reclaims() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        info.settings.claim_minutes = 0;
+        let gpus = [nvidia::Nvidia::new(0)];
+        info.add_reservation(slot_user("s@s.com", "2030-01-01 10:00:00", 60));
+        //预计3小时的预约会占用10点的时段，不分配；1小时的可以
+        let long = info.add_reservation(UserWrapper::from(
+            User::new(
+                String::from("a@a.com"),
+                Some(now.date()),
+                Some(now.time()),
+                false,
+            )
+            .with_duration(Some(180)),
+        ));
+        assert!(info.get_new_user(now, 1).is_none());
+        assert!(info.get_new_user(now, 2).is_some());
+        info.reservations.get_mut(&long).unwrap().duration = None;
+
+        //未申报时长的持有者在时段开始时被收回，优先重新排队
+        info.update_current_user(&gpus, now);
+        assert_eq!(info.curr_users[&0].id, long);
+        let start = now + Duration::hours(2);
+        info.update_current_user(&gpus, start);
+        assert_eq!(info.curr_users[&0].email, "s@s.com");
+        let requeued = &info.reservations[&long];
+        assert!(requeued.requeued && requeued.grant_time.is_none());
+    }
+
+    #[test]
+    fn test_preemption() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        info.settings.preempt_grace_minutes = 30;
+        let mut holder = UserWrapper::from(User::new(String::from("a@a.com"), None, None, false));
+        holder.grant_time = Some(now.to_string());
+        holder.id = info.add_reservation(holder.clone());
+        info.reservations.get_mut(&holder.id).unwrap().grant_time = holder.grant_time.clone();
+        info.curr_users.insert(0, holder.clone());
+        info.add_reservation(UserWrapper::from(User::new(
+            String::from("b@b.com"),
+            None,
+            None,
+            true,
+        )));
+
+        //未启用抢占时不处理
+        info.check_preemption(now, 1);
+        assert!(info.reservations[&holder.id].preempt_at.is_none());
+
+        //先警告，宽限期内仍持有
+        info.settings.preempt = true;
+        info.check_preemption(now, 1);
+        let preempt_at = (now + Duration::minutes(30)).to_string();
+        assert_eq!(info.reservations[&holder.id].preempt_at, Some(preempt_at));
+        //主循环每轮将预约同步到持有者
+        info.update_current_user(&[], now);
+        info.check_preemption(now + Duration::minutes(29), 1);
+        assert!(info.curr_users.contains_key(&0));
+
+        //宽限期过后收回并优先重新排队
+        info.check_preemption(now + Duration::minutes(30), 1);
+        assert!(info.curr_users.is_empty());
+        let requeued = &info.reservations[&holder.id];
+        assert!(
+            requeued.requeued && requeued.grant_time.is_none() && requeued.preempt_at.is_none()
+        );
+    }
+
+    #[test]
+    fn test_idle_release() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 09:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        info.settings.idle_release_minutes = 60;
+        //诊断计时按卡号全局保存，使用其他测试不用的卡号
+        let index = 7;
+        let mut gpus = [nvidia::Nvidia::with_reading(index, 0, 100, 10000)];
+        let mut holder = UserWrapper::from(User::new(String::from("a@a.com"), None, None, false));
+        holder.grant_time = Some(now.to_string());
+        holder.id = info.add_reservation(holder.clone());
+        info.curr_users.insert(index, holder.clone());
+
+        //连续空闲读数达到阈值后通知
+        for _ in 0..=config::DEVICE_FREE {
+            info.diagnose(&mut gpus, now);
+        }
+        assert_eq!(info.events.last().unwrap().kind, EventKind::IdleWarning);
+        info.diagnose(&mut gpus, now + Duration::minutes(59));
+        assert!(!info.reservations[&holder.id].finish);
+        info.diagnose(&mut gpus, now + Duration::minutes(60));
+        assert!(info.reservations[&holder.id].finish);
+        assert_eq!(info.releases.len(), 1);
+        assert_eq!(info.releases[0].reason, "idle");
+
+        for _ in 0..config::MAX_RELEASES {
+            info.record_release(info.releases[0].clone());
+        }
+        assert_eq!(info.releases.len(), config::MAX_RELEASES);
+    }
+
+    #[test]
+    fn test_repeat_needs_approval() {
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        info.settings.admins = vec![String::from("admin@lab.com")];
+        info.settings.approval_max_minutes = 60;
+        let rule = Recurrence::Daily;
+        //每天48小时的各次相互重叠，直接拒绝
+        let overlapping = slot_user("a@a.com", "2030-01-01 08:00:00", 48 * 60);
+        let reply = info.handle_repeat(overlapping, rule.clone(), "2030-01-05", 1);
+        assert_eq!(reply.code, Some(ErrorCode::BadRequest));
+        assert!(info.reservations.is_empty());
+        let long = slot_user("a@a.com", "2030-01-01 08:00:00", 90);
+        assert!(info.handle_repeat(long, rule.clone(), "2030-01-03", 4).ok);
+        assert_eq!(info.reservations.len(), 3);
+        assert!(info.reservations.values().all(|x| x.pending));
+        let short = slot_user("b@b.com", "2030-02-01 08:00:00", 30);
+        assert!(info.handle_repeat(short, rule, "2030-02-02", 4).ok);
+        let approved = info.reservations.values().filter(|x| !x.pending).count();
+        assert_eq!(approved, 2);
+    }
+
+    #[test]
+    fn test_claim_ambiguous() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        let gpus = [nvidia::Nvidia::new(0), nvidia::Nvidia::new(1)];
+        for _ in 0..2 {
+            info.add_reservation(UserWrapper::from(User::new(
+                String::from("a@a.com"),
+                Some(now.date()),
+                Some(now.time()),
+                false,
+            )));
+        }
+        info.update_current_user(&gpus, now);
+        let reply = info.handle_claim("a@a.com", 0, None, now);
+        assert_eq!(reply.code, Some(ErrorCode::Ambiguous));
+        assert!(info.handle_claim("a@a.com", 2, None, now).ok);
+        //只剩一个待领取时可省略编号
+        assert_eq!(info.handle_claim("a@a.com", 0, None, now).id, Some(1));
+        let reply = info.handle_claim("a@a.com", 0, None, now);
+        assert_eq!(reply.code, Some(ErrorCode::NotFound));
+    }
+
+    #[test]
+    fn test_future_user_not_granted_early() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let start = now + Duration::hours(2);
+        let mut info = AppInfo::new();
+        let user = UserWrapper::from(User::new(
+            String::from("a@a.com"),
+            Some(start.date()),
+            Some(start.time()),
+            false,
+        ));
+        let id = info.add_reservation(user);
+        assert!(info.get_new_user(now, 1).is_none());
+        assert!(info.get_new_user(start, 1).is_some());
+
+        info.reservations.get_mut(&id).unwrap().early = true;
+        info.settings.lookahead_minutes = 60;
+        assert!(info.get_new_user(now, 1).is_none());
+        assert!(info.get_new_user(now + Duration::hours(1), 1).is_some());
+    }
+
+    #[test]
+    fn test_multiple_reservations() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        let user = UserWrapper::from(User::new(
+            String::from("a@a.com"),
+            Some(now.date()),
+            Some(now.time()),
+            false,
+        ));
+        let first = info.add_reservation(user.clone());
+        let second = info.add_reservation(user);
+        assert_ne!(first, second);
+
+        let mut holder = info.get_new_user(now, 1).unwrap();
+        assert_eq!(holder.id, first);
+        holder.grant_time = Some(now.to_string());
+        info.curr_users.insert(0, holder);
+        assert_eq!(info.get_new_user(now, 1).unwrap().id, second);
+    }
+
+    #[test]
+    fn test_edf_orders_by_slack() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.settings.policy = config::Policy::Edf;
+        //a松弛1小时，b松弛30分钟，c已无法按时完成，d为紧急预约但无截止时刻
+        for (email, minutes, deadline, urg) in [
+            ("a@a.com", 120, Some(3), false),
+            ("b@b.com", 270, Some(5), false),
+            ("c@c.com", 240, Some(1), false),
+            ("d@d.com", 60, None, true),
+        ] {
+            info.add_reservation(UserWrapper::from(
+                User::new(email.to_string(), Some(now.date()), Some(now.time()), urg)
+                    .with_duration(Some(minutes))
+                    .with_deadline(deadline.map(|x| now + Duration::hours(x))),
+            ));
+        }
+        let mut users: Vec<&UserWrapper> = info.reservations.values().collect();
+        users.sort_by(|a, b| info.compare(b, a, now));
+        let emails: Vec<&str> = users.iter().map(|x| x.email.as_str()).collect();
+        assert_eq!(emails, vec!["b@b.com", "a@a.com", "d@d.com", "c@c.com"]);
+
+        //截止时刻能插队，配置管理员时须经审批
+        info.settings.admins = vec![String::from("admin@lab.com")];
+        let a = info
+            .reservations
+            .values()
+            .find(|x| x.email == "a@a.com")
+            .unwrap();
+        assert!(info.need_approval(a));
+        info.settings.policy = config::Policy::Fair;
+        assert!(!info.need_approval(a));
+    }
+
+    #[test]
+    fn test_no_show_moves_back() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        for (email, timestamp) in [("a@a.com", 1), ("b@b.com", 2)] {
+            let mut user = UserWrapper::from(User::new(
+                email.to_string(),
+                Some(now.date()),
+                Some(now.time()),
+                false,
+            ));
+            user.timestamp = timestamp;
+            info.add_reservation(user);
+        }
+        assert_eq!(info.get_new_user(now, 1).unwrap().email, "a@a.com");
+        info.reservations.get_mut(&1).unwrap().no_shows = 1;
+        assert_eq!(info.get_new_user(now, 1).unwrap().email, "b@b.com");
+    }
+
+    #[test]
+    fn test_modify_policy() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        let mut holder = UserWrapper::from(
+            User::new(String::from("a@a.com"), None, None, false).with_duration(Some(60)),
+        );
+        holder.grant_time = Some(now.to_string());
+        holder.id = info.add_reservation(holder.clone());
+        info.curr_users.insert(0, holder.clone());
+
+        let mut longer = holder.clone();
+        longer.duration = Some(120);
+        assert!(info.check_modify(&holder, &longer, 1, now).is_ok());
+        let mut moved = holder.clone();
+        moved.date_time = (now + Duration::hours(1)).to_string();
+        assert!(info.check_modify(&holder, &moved, 1, now).is_err());
+
+        //有人排队时不能延长，但可以缩短
+        info.add_reservation(UserWrapper::from(User::new(
+            String::from("b@b.com"),
+            Some(now.date()),
+            Some(now.time()),
+            false,
+        )));
+        assert!(info.check_modify(&holder, &longer, 1, now).is_err());
+        let mut shorter = holder.clone();
+        shorter.duration = Some(30);
+        assert!(info.check_modify(&holder, &shorter, 1, now).is_ok());
+
+        //改期不能与其他时段预约冲突
+        let slot = slot_user("c@c.com", "2030-01-02 14:00:00", 120);
+        info.add_reservation(slot_user("d@d.com", "2030-01-02 16:00:00", 60));
+        let mut later = slot.clone();
+        later.date_time = String::from("2030-01-02 15:00:00");
+        assert!(info.check_modify(&slot, &later, 1, now).is_err());
+        later.date_time = String::from("2030-01-02 17:00:00");
+        assert!(info.check_modify(&slot, &later, 1, now).is_ok());
+
+        info.dry_run = true;
+        let start = Some(String::from("tomorrow"));
+        let reply = info.handle_modify("a@a.com", holder.id, None, start, 1, now);
+        assert_eq!(reply.code, Some(ErrorCode::BadRequest));
+    }
+
+    #[test]
+    fn test_query_whoami() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        let mut holder = UserWrapper::from(User::new(
+            String::from("a@a.com"),
+            Some(now.date()),
+            Some(now.time()),
+            false,
+        ));
+        holder.grant_time = Some(now.to_string());
+        holder.id = info.add_reservation(holder.clone());
+        info.curr_users.insert(0, holder.clone());
+        info.add_reservation(holder.clone());
+        info.add_reservation(slot_user("b@b.com", "2030-01-02 08:00:00", 60));
+
+        let reply = info.handle_query(&Query::Whoami, "a@a.com", None, 1, now);
+        assert!(reply.ok);
+        assert_eq!(
+            reply.data.unwrap()["reservations"]
+                .as_array()
+                .unwrap()
+                .len(),
+            2
+        );
+        assert!(reply.message.contains("显卡0"));
+        assert!(reply.message.contains("排队第1位"));
+        let reply = info.handle_query(&Query::Queue, "", None, 1, now);
+        assert_eq!(reply.data.unwrap()["queue"].as_array().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_usage_decay() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.record_usage("a@a.com", 10.0, now);
+        let later = now + Duration::hours(info.settings.half_life_hours as i64);
+        assert!((info.decayed_usage("a@a.com", later) - 5.0).abs() < 1e-6);
+        assert_eq!(info.decayed_usage("b@b.com", later), 0.0);
+    }
+
+    #[test]
+    fn test_estimate_queue() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        let mut holder = UserWrapper::from(
+            User::new(String::from("a@a.com"), None, None, false).with_duration(Some(120)),
+        );
+        holder.grant_time = Some(now.to_string());
+        let id = info.add_reservation(holder.clone());
+        holder.id = id;
+        info.curr_users.insert(0, holder);
+        for (email, timestamp, duration) in [("b@b.com", 1, Some(60)), ("c@c.com", 2, None)] {
+            let mut user = UserWrapper::from(
+                User::new(email.to_string(), Some(now.date()), Some(now.time()), false)
+                    .with_duration(duration),
+            );
+            user.timestamp = timestamp;
+            info.add_reservation(user);
+        }
+
+        let estimates = info.estimate_queue(now, 1);
+        assert_eq!(estimates.len(), 2);
+        assert_eq!(estimates[0].email, "b@b.com");
+        assert_eq!(estimates[0].eta.as_deref(), Some("2030-01-01 10:00:00"));
+        assert_eq!(estimates[1].position, 2);
+        assert_eq!(estimates[1].eta.as_deref(), Some("2030-01-01 11:00:00"));
+    }
+
+    #[test]
+    fn test_admin_auth() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let path = std::env::temp_dir().join(format!("RustTip-token-{}", std::process::id()));
+        std::fs::write(&path, "secret\n").unwrap();
+        let mut info = AppInfo::new();
+        info.settings.admin_uids = vec![1000];
+        info.settings.admin_token_file = path.to_string_lossy().to_string();
+        let stop = |token: Option<&str>| Request::Admin {
+            token: token.map(|token| token.to_string()),
+            command: AdminCommand::Stop,
+        };
+
+        assert!(info.handle(stop(None), Some(1000), 1, now).ok);
+        let reply = info.handle(stop(None), Some(1001), 1, now);
+        assert_eq!(reply.code, Some(ErrorCode::Forbidden));
+        assert_eq!(info.denials.len(), 1);
+        //令牌文件仅所有者可读时才采信
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o644)).unwrap();
+        assert!(!info.handle(stop(Some("secret")), None, 1, now).ok);
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600)).unwrap();
+        assert!(info.handle(stop(Some("secret")), None, 1, now).ok);
+        assert!(!info.handle(stop(Some("guess")), None, 1, now).ok);
+        assert_eq!(info.denials.len(), 3);
+        std::fs::remove_file(&path).ok();
+    }
+
+    #[test]
+    fn test_approval_auth() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        info.settings.admins = vec![String::from("admin@lab.com")];
+        info.settings.admin_uids = vec![1000];
+        let mut ids = Vec::new();
+        for _ in 0..2 {
+            let mut user = UserWrapper::from(User::new(String::from("a@a.com"), None, None, true));
+            user.pending = true;
+            ids.push(info.add_reservation(user));
+        }
+        let approve = |id: u64| Request::Admin {
+            token: None,
+            command: AdminCommand::Approve {
+                admin: String::from("admin@lab.com"),
+                id,
+            },
+        };
+
+        //普通用户冒用管理员邮箱也无法审批自己的预约
+        let reply = info.handle(approve(ids[0]), Some(1001), 1, now);
+        assert_eq!(reply.code, Some(ErrorCode::Forbidden));
+        assert!(info.reservations[&ids[0]].pending);
+        assert_eq!(info.denials.len(), 1);
+        //反复请求的拒绝记录有上限
+        for _ in 0..config::MAX_DENIALS + 10 {
+            info.handle(approve(ids[0]), Some(1001), 1, now);
+        }
+        assert_eq!(info.denials.len(), config::MAX_DENIALS);
+
+        assert!(info.handle(approve(ids[0]), Some(1000), 1, now).ok);
+        assert!(!info.reservations[&ids[0]].pending);
+        let reject = Request::Admin {
+            token: None,
+            command: AdminCommand::Reject {
+                admin: String::from("admin@lab.com"),
+                id: ids[1],
+            },
+        };
+        assert!(info.handle(reject, Some(1000), 1, now).ok);
+        assert!(info.reservations[&ids[1]].finish);
+        //已处理的预约不能再次审批
+        let reply = info.handle(approve(ids[1]), Some(1000), 1, now);
+        assert_eq!(reply.code, Some(ErrorCode::NotFound));
+    }
+
+    #[test]
+    fn test_api_tokens() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.settings.admin_uids = vec![1000];
+        let issue = Request::Admin {
+            token: None,
+            command: AdminCommand::IssueToken {
+                email: String::from("a@a.com"),
+            },
+        };
+        let reply = info.handle(issue, Some(1000), 1, now);
+        let token = reply.data.unwrap()["token"].as_str().unwrap().to_string();
+        let whoami = |email: &str| Request::Query {
+            email: email.to_string(),
+            query: Query::Whoami,
+        };
+
+        assert!(info.check_token(&whoami("a@a.com"), Some(&token)).is_ok());
+        //只保存摘要，info.json中没有令牌明文
+        assert!(!serde_json::to_string(&info).unwrap().contains(&token));
+        let reply = info
+            .check_token(&whoami("b@b.com"), Some(&token))
+            .unwrap_err();
+        assert_eq!(reply.code, Some(ErrorCode::Forbidden));
+        assert!(info.check_token(&whoami("a@a.com"), None).is_err());
+        assert!(info.check_token(&whoami("a@a.com"), Some("guess")).is_err());
+        let revoke = Request::Admin {
+            token: None,
+            command: AdminCommand::RevokeToken {
+                email: String::from("a@a.com"),
+            },
+        };
+        assert!(info.handle(revoke, Some(1000), 1, now).ok);
+        assert!(info.check_token(&whoami("a@a.com"), Some(&token)).is_err());
+    }
+
+    #[test]
+    fn test_identity_without_uid() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.settings.admin_uids = vec![1000];
+        let issue = Request::Admin {
+            token: None,
+            command: AdminCommand::IssueToken {
+                email: String::from("a@a.com"),
+            },
+        };
+        let reply = info.handle(issue, Some(1000), 1, now);
+        let token = reply.data.unwrap()["token"].as_str().unwrap().to_string();
+        let finish = Request::Finish {
+            email: String::from("a@a.com"),
+            id: None,
+        };
+        let status = Request::Query {
+            email: String::new(),
+            query: Query::Status,
+        };
+
+        //本地套接字可读取uid，无需令牌；预约归属在处理时按uid检查，见test_owner_uid
+        assert!(info
+            .check_identity(&finish, Some(1001), false, None)
+            .is_ok());
+        //本机TCP与HTTP读不到uid，冒用他人邮箱被拒绝
+        let reply = info.check_identity(&finish, None, false, None).unwrap_err();
+        assert_eq!(reply.code, Some(ErrorCode::Forbidden));
+        assert!(info
+            .check_identity(&finish, None, false, Some(&token))
+            .is_ok());
+        //不涉及身份的查询照常应答
+        assert!(info.check_identity(&status, None, false, None).is_ok());
+        assert!(info.check_identity(&status, None, true, None).is_err());
+    }
+
+    #[test]
+    fn test_reorder() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        for (email, timestamp) in [("a@a.com", 1), ("b@b.com", 2), ("c@c.com", 3)] {
+            let mut user = UserWrapper::from(User::new(
+                email.to_string(),
+                Some(now.date()),
+                Some(now.time()),
+                false,
+            ));
+            user.timestamp = timestamp;
+            info.add_reservation(user);
+        }
+        let reply = info.handle_reorder(3, 1, now);
+        assert_eq!(reply.position, Some(1));
+        let order: Vec<u64> = info.waiting(now).iter().map(|user| user.id).collect();
+        assert_eq!(order, vec![3, 1, 2]);
+        assert_eq!(info.get_new_user(now, 1).unwrap().email, "c@c.com");
+        assert!(!info.handle_reorder(4, 1, now).ok);
+
+        //不在排队中的预约也清除上一次留下的位置，重新排队时不再插到前面
+        info.reservations.get_mut(&3).unwrap().pending = true;
+        let reply = info.handle_reorder(2, 1, now);
+        assert_eq!(reply.position, Some(1));
+        assert_eq!(info.reservations[&3].order, None);
+        info.reservations.get_mut(&3).unwrap().pending = false;
+        let order: Vec<u64> = info.waiting(now).iter().map(|user| user.id).collect();
+        assert_eq!(order, vec![2, 1, 3]);
+
+        //已开始的时段预约排在调整位置之前，应答实际位置
+        let slot = info.add_reservation(slot_user("d@d.com", "2030-01-01 08:00:00", 60));
+        let reply = info.handle_reorder(1, 1, now);
+        assert_eq!(reply.position, Some(2));
+        let order: Vec<u64> = info.waiting(now).iter().map(|user| user.id).collect();
+        assert_eq!(order, vec![slot, 1, 2, 3]);
+    }
+
+    #[test]
+    fn test_subscribers() {
+        let server = Server::new(String::new(), String::new());
+        let event = |email: &str| {
+            Event::new(EventKind::Booked, String::new(), String::from("受理")).with_user(email, 1)
+        };
+        let (conn, mut peer) = UnixStream::pair().unwrap();
+        let (closed, closed_peer) = UnixStream::pair().unwrap();
+        let mut subscribers = vec![
+            Subscriber::spawn(Some(String::from("a@a.com")), Connection::Unix(conn)),
+            Subscriber::spawn(Some(String::from("b@b.com")), Connection::Unix(closed)),
+        ];
+        //过滤掉全部事件的订阅者断开后同样被移除
+        drop(closed_peer);
+        thread::sleep(time::Duration::from_millis(1500));
+        server.broadcast(&mut subscribers, vec![event("a@a.com")]);
+        assert_eq!(subscribers.len(), 1);
+        let mut line = String::new();
+        BufReader::new(&mut peer).read_line(&mut line).unwrap();
+        assert!(line.contains("a@a.com"));
+        //对端不读取时写线程阻塞，积压超过上限的订阅者被断开，不阻塞推送
+        let events = (0..config::SUBSCRIBER_QUEUE * 4)
+            .map(|_| {
+                Event::new(EventKind::Booked, String::new(), "x".repeat(4096))
+                    .with_user("a@a.com", 1)
+            })
+            .collect();
+        server.broadcast(&mut subscribers, events);
+        assert!(subscribers[0].sender.is_none());
+    }
+
+    #[test]
+    fn test_serve_limits() {
+        //逐字节慢速发送也在整行期限内断开
+        let (conn, mut peer) = UnixStream::pair().unwrap();
+        let drip = thread::spawn(move || {
+            for _ in 0..20 {
+                if peer.write_all(b"{").is_err() {
+                    break;
+                }
+                thread::sleep(time::Duration::from_millis(100));
+            }
+        });
+        let mut conn = Connection::Unix(conn);
+        let started = time::Instant::now();
+        let reader = DeadlineReader {
+            conn: &mut conn,
+            deadline: started + time::Duration::from_millis(500),
         };
+        assert!(protocol::read_line(&mut BufReader::new(reader), 1024).is_err());
+        assert!(started.elapsed() < time::Duration::from_millis(1000));
+        drop(conn);
+        drip.join().unwrap();
 
-        //任务结束通知，每段时间通知一次，指数增长
-        let bound = now >= start_time && now <= end_time && time_diff && self.curr_user.is_some();
-        gpu.read_from_terminal();
-        if gpu.is_free() && bound {
-            *last_time = Some(now);
-            *gap = *gap * 2;
-            if *gap > Duration::minutes(config::TIME_GAP_MAX_MINUTES) {
-                *gap = Duration::minutes(config::TIME_GAP_MAX_MINUTES);
+        //HTTP请求头慢速发送同样按整个请求的期限断开
+        let (conn, mut peer) = UnixStream::pair().unwrap();
+        let drip = thread::spawn(move || {
+            peer.write_all(b"GET / HTTP/1.1\r\n").ok();
+            for _ in 0..20 {
+                if peer.write_all(b"X").is_err() {
+                    break;
+                }
+                thread::sleep(time::Duration::from_millis(100));
             }
-            self.send_email(self.curr_user.as_ref().unwrap().email.clone(), "设备空闲通知",
-            &format! {"用户{}设备空闲，请在服务器进行确认！",self.curr_user.as_ref().unwrap().email});
+        });
+        let mut conn = Connection::Unix(conn);
+        let started = time::Instant::now();
+        let reader = DeadlineReader {
+            conn: &mut conn,
+            deadline: started + time::Duration::from_millis(500),
+        };
+        assert!(HttpRequest::read(&mut BufReader::new(reader)).is_err());
+        assert!(started.elapsed() < time::Duration::from_millis(1000));
+        drop(conn);
+        drip.join().unwrap();
+
+        //HTTP与远程连接占满各自的计数池时不影响本机连接
+        let http: Vec<ConnectionSlot> = (0..config::MAX_CONNECTIONS)
+            .map_while(|_| ConnectionSlot::acquire(&HTTP_CONNECTIONS))
+            .collect();
+        assert_eq!(http.len(), config::MAX_CONNECTIONS);
+        assert!(ConnectionSlot::acquire(&HTTP_CONNECTIONS).is_none());
+        assert!(ConnectionSlot::acquire(&CONNECTIONS).is_some());
+        drop(http);
+        assert!(ConnectionSlot::acquire(&HTTP_CONNECTIONS).is_some());
+
+        //重复握手被拒绝并断开
+        let (conn, mut peer) = UnixStream::pair().unwrap();
+        let server = thread::spawn(move || App::serve(Connection::Unix(conn)));
+        let hello = serde_json::to_string(&Envelope::new(Request::Hello)).unwrap() + "\n";
+        let mut reader = BufReader::new(peer.try_clone().unwrap());
+        let mut replies = Vec::new();
+        for _ in 0..2 {
+            peer.write_all(hello.as_bytes()).unwrap();
+            let mut line = String::new();
+            reader.read_line(&mut line).unwrap();
+            replies.push(serde_json::from_str::<Reply>(&line).unwrap());
         }
-        if gpu.is_low_efficiency() && bound {
-            *last_time = Some(now);
-            *gap = *gap * 2;
-            if *gap > Duration::minutes(config::TIME_GAP_MAX_MINUTES) {
-                *gap = Duration::minutes(config::TIME_GAP_MAX_MINUTES);
+        server.join().unwrap();
+        assert!(replies[0].ok);
+        assert_eq!(replies[1].code, Some(ErrorCode::BadRequest));
+    }
+
+    #[test]
+    fn test_timestamp_from_server() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        //客户端自带的时间戳被忽略，不能借此排到队首
+        let line = r#"{"version":4,"request":{"Book":{"email":"a@a.com","date_time":"2030-01-01 08:00:00","timestamp":0,"duration":60,"slot":false,"early":false,"deadline":null}}}"#;
+        let envelope = protocol::parse(line).unwrap();
+        let reply = info.handle(envelope.request, None, 1, now);
+        let expected = Local
+            .from_local_datetime(&now)
+            .earliest()
+            .unwrap()
+            .timestamp();
+        assert_eq!(info.reservations[&reply.id.unwrap()].timestamp, expected);
+    }
+
+    #[test]
+    fn test_owner_uid() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        info.settings.admin_uids = vec![0];
+        let book = |start: &str| {
+            Request::Book(Booking::from(
+                User::new(
+                    String::from("a@a.com"),
+                    None,
+                    Some(NaiveTime::parse_from_str(start, "%H:%M:%S").unwrap()),
+                    false,
+                )
+                .with_duration(Some(60)),
+            ))
+        };
+        let first = info
+            .handle(book("09:00:00"), Some(1000), 2, now)
+            .id
+            .unwrap();
+        let second = info.handle(book("10:00:00"), Some(1000), 2, now).id;
+        let finish = |id| Request::Finish {
+            email: String::from("a@a.com"),
+            id,
+        };
+        let modify = Request::Modify {
+            email: String::from("a@a.com"),
+            id: Some(first),
+            duration: Some(30),
+            start: None,
+        };
+
+        //其他本地用户不能操作该uid提交的预约
+        let reply = info.handle(finish(Some(first)), Some(1001), 2, now);
+        assert_eq!(reply.code, Some(ErrorCode::Forbidden));
+        let reply = info.handle(modify.clone(), Some(1001), 2, now);
+        assert_eq!(reply.code, Some(ErrorCode::Forbidden));
+        assert!(!info.reservations[&first].finish);
+        //提交者本人与管理员uid可以操作
+        assert!(info.handle(modify, Some(1000), 2, now).ok);
+        assert!(info.handle(finish(Some(first)), Some(1000), 2, now).ok);
+        assert!(info.handle(finish(second), Some(0), 2, now).ok);
+    }
+
+    #[test]
+    fn test_submit_write_timeout() {
+        //交给主循环的连接都带写入超时，客户端不读取应答时主循环不会一直阻塞
+        let (conn, _peer) = UnixStream::pair().unwrap();
+        let marker = Envelope::new(Request::Query {
+            email: String::from("timeout@a.com"),
+            query: Query::Whoami,
+        });
+        App::submit(marker.clone(), None, Connection::Unix(conn));
+        let mut received = RECV_DATA.lock().unwrap();
+        let at = received
+            .iter()
+            .position(|(envelope, _, _)| *envelope == marker)
+            .unwrap();
+        let (_, _, conn) = received.remove(at);
+        match conn {
+            Connection::Unix(stream) => assert_eq!(
+                stream.write_timeout().unwrap(),
+                Some(time::Duration::from_secs(config::REPLY_TIMEOUT_SECONDS))
+            ),
+            _ => unreachable!(),
+        }
+    }
+
+    #[test]
+    fn test_migrate_baseline() {
+        //最初版本的info.json只有唯一持有者curr_user与以email为键的user_info
+        let holder = r#"{"urg":false,"finish":false,"timestamp":1,"email":"a@a.com","date_time":"2030-01-01 08:00:00"}"#;
+        let waiting = r#"{"urg":false,"finish":false,"timestamp":2,"email":"b@b.com","date_time":"2030-01-01 08:00:00"}"#;
+        let data = format!(
+            r#"{{"server_info":{{"account":"x@qq.com","password":"pw"}},"curr_user":{},"user_info":{{"a@a.com":{},"b@b.com":{}}}}}"#,
+            holder, holder, waiting
+        );
+        let mut info: AppInfo = serde_json::from_str(&data).unwrap();
+        info.migrate();
+        assert_eq!(info.reservations.len(), 2);
+        let holder = &info.curr_users[&0];
+        assert_eq!(holder.email, "a@a.com");
+        assert_eq!(info.reservations[&holder.id].email, "a@a.com");
+        assert!(!serde_json::to_string(&info)
+            .unwrap()
+            .contains("curr_user\""));
+    }
+
+    #[test]
+    fn test_record_history() {
+        let start =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        for second in 0..(config::HISTORY_STEP_SECONDS * 3) {
+            info.record_history(start + Duration::seconds(second));
+        }
+        assert_eq!(info.history.len(), 3);
+        for step in 3..(config::HISTORY_SAMPLES as i64 + 10) {
+            info.record_history(start + Duration::seconds(step * config::HISTORY_STEP_SECONDS));
+        }
+        assert_eq!(info.history.len(), config::HISTORY_SAMPLES);
+        let reply = info.handle_query(&Query::Dashboard, "", None, 1, start);
+        assert_eq!(
+            reply.data.unwrap()["history"].as_array().unwrap().len(),
+            config::HISTORY_SAMPLES
+        );
+    }
+
+    #[test]
+    fn test_holder_events() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        info.settings.claim_minutes = 0;
+        let gpus = [nvidia::Nvidia::new(0)];
+        let id = info.add_reservation(UserWrapper::from(User::new(
+            String::from("a@a.com"),
+            Some(now.date()),
+            Some(now.time()),
+            false,
+        )));
+
+        let holders = info.curr_users.clone();
+        info.update_current_user(&gpus, now);
+        info.emit_holder_changes(&holders, now);
+        let holders = info.curr_users.clone();
+        info.reservations.get_mut(&id).unwrap().finish = true;
+        info.update_current_user(&gpus, now);
+        info.emit_holder_changes(&holders, now);
+        let kinds: Vec<EventKind> = info.events.iter().map(|x| x.kind).collect();
+        assert_eq!(kinds, vec![EventKind::Granted, EventKind::Released]);
+        assert!(info.events[0].matches(Some("a@a.com")));
+        assert!(!info.events[0].matches(Some("b@b.com")));
+        //读数事件不属于任何用户，只推送给未过滤的订阅者
+        info.record_history(now);
+        let metrics = info.events.last().unwrap();
+        assert!(metrics.matches(None) && !metrics.matches(Some("a@a.com")));
+    }
+
+    //由边界取值随机拼出请求，通过解析的请求交给主循环处理，不应panic
+    #[test]
+    fn test_fuzz_handle() {
+        let now =
+            NaiveDateTime::parse_from_str("2030-01-01 08:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let mut info = AppInfo::new();
+        info.dry_run = true;
+        info.settings.admin_uids = vec![0];
+        let gpus = [nvidia::Nvidia::new(0), nvidia::Nvidia::new(1)];
+        let times = [
+            "\"2030-01-01 08:00:00\"",
+            "\"1970-01-01 00:00:00\"",
+            "\"9999-12-31 23:59:59\"",
+            "\"2030-02-30 08:00:00\"",
+            "\"\"",
+            "null",
+        ];
+        let numbers = [
+            "0",
+            "1",
+            "60",
+            "-1",
+            "527040",
+            "9223372036854775807",
+            "1.5",
+            "null",
+        ];
+        let emails = ["\"a@a.com\"", "\"b@b.com\"", "\"x\"", "\"\""];
+        let mut state = 0x9e37_79b9_7f4a_7c15;
+        let mut pick = |values: &[&'static str]| {
+            values[protocol::tests::xorshift(&mut state) as usize % values.len()]
+        };
+        for _ in 0..1000 {
+            let booking = format!(
+                r#"{{"email":{},"date_time":{},"timestamp":{},"duration":{},"slot":{},"early":false,"deadline":{}}}"#,
+                pick(&emails),
+                pick(&times),
+                pick(&numbers),
+                pick(&numbers),
+                pick(&["true", "false"]),
+                pick(&times),
+            );
+            let request = match pick(&[
+                "book", "urgent", "repeat", "finish", "modify", "claim", "reorder", "release",
+            ]) {
+                "book" => format!(r#"{{"Book":{}}}"#, booking),
+                "urgent" => format!(r#"{{"Urgent":{}}}"#, booking),
+                "repeat" => format!(
+                    r#"{{"Repeat":{{"booking":{},"rule":"Daily","until":"2030-01-0{}"}}}}"#,
+                    booking,
+                    pick(&["1", "3", "0"])
+                ),
+                "finish" => format!(
+                    r#"{{"Finish":{{"email":{},"id":{}}}}}"#,
+                    pick(&emails),
+                    pick(&numbers)
+                ),
+                "modify" => format!(
+                    r#"{{"Modify":{{"email":{},"id":{},"duration":{},"start":{}}}}}"#,
+                    pick(&emails),
+                    pick(&numbers),
+                    pick(&numbers),
+                    pick(&times)
+                ),
+                "claim" => format!(
+                    r#"{{"Claim":{{"email":{},"id":{}}}}}"#,
+                    pick(&emails),
+                    pick(&numbers)
+                ),
+                "reorder" => format!(
+                    r#"{{"Admin":{{"token":null,"command":{{"Reorder":{{"id":{},"position":{}}}}}}}}}"#,
+                    pick(&numbers),
+                    pick(&numbers)
+                ),
+                _ => format!(
+                    r#"{{"Admin":{{"token":null,"command":{{"Release":{{"id":{}}}}}}}}}"#,
+                    pick(&numbers)
+                ),
+            };
+            let line = format!(
+                r#"{{"version":{},"request":{}}}"#,
+                protocol::VERSION,
+                request
+            );
+            if let Ok(envelope) = protocol::parse(&line) {
+                info.handle(envelope.request, Some(0), gpus.len(), now);
             }
-            self.send_email(self.curr_user.as_ref().unwrap().email.clone(), "任务效率通知",
-            &format! {"用户{}当前设备运行效率较低，请检查！",self.curr_user.as_ref().unwrap().email});
+            info.check_expiry(now);
+            info.check_preemption(now, gpus.len());
+            info.check_claims(now);
+            info.update_current_user(&gpus, now);
+            info.queue = info.estimate_queue(now, gpus.len());
+            info.check_deadlines();
         }
     }
 }
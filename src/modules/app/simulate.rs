@@ -0,0 +1,326 @@
+use super::protocol::{Booking, Request};
+use super::{config, nvidia, AppInfo, User, UserWrapper};
+use chrono::{prelude::*, Duration};
+use serde::Deserialize;
+use std::collections::BTreeMap;
+use std::fmt;
+
+//轨迹文件中的一条记录，每行一个JSON对象
+//Eg: {"time":"2030-01-01 08:00:00","email":"a@a.com","hours":2,"run":1.5}
+#[derive(Deserialize, Debug, Clone)]
+struct Event {
+    time: String, //提交时刻
+    email: String,
+    #[serde(default)]
+    urg: bool,
+    #[serde(default)]
+    finish: bool, //注销该用户唯一的预约
+    #[serde(default)]
+    start: Option<String>, //开始时刻，缺省为提交时刻
+    #[serde(default)]
+    hours: Option<f64>, //申报时长
+    #[serde(default)]
+    run: Option<f64>, //实际运行时长，缺省为申报时长
+    #[serde(default)]
+    slot: bool,
+    #[serde(default)]
+    deadline: Option<String>,
+}
+
+fn parse_time(time: &str) -> Result<NaiveDateTime, String> {
+    NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S")
+        .map_err(|_| format!("时间格式错误: {}", time))
+}
+
+impl Event {
+    fn to_request(&self) -> Result<Request, String> {
+        if self.finish {
+            return Ok(Request::Finish {
+                email: self.email.clone(),
+                id: None,
+            });
+        }
+        let time = parse_time(&self.time)?;
+        let start = match &self.start {
+            Some(start) => parse_time(start)?,
+            None => time,
+        };
+        let deadline = match &self.deadline {
+            Some(deadline) => Some(parse_time(deadline)?),
+            None => None,
+        };
+        let booking = Booking::from(
+            User::new(
+                self.email.clone(),
+                Some(start.date()),
+                Some(start.time()),
+                self.urg,
+            )
+            .with_duration(self.hours.map(|hours| (hours * 60.0).round() as i64))
+            .with_slot(self.slot)
+            .with_deadline(deadline),
+        );
+        Ok(if self.urg {
+            Request::Urgent(booking)
+        } else {
+            Request::Book(booking)
+        })
+    }
+}
+
+//模拟结果：分配时间线、各用户等待时间与显卡利用率
+#[derive(Debug, Default)]
+struct Report {
+    timeline: Vec<String>,
+    waits: BTreeMap<String, Vec<i64>>, //email到每次等待分钟数
+    busy: BTreeMap<u32, Duration>,     //各显卡被占用的总时长
+    devices: u32,
+    start: Option<NaiveDateTime>,
+    end: Option<NaiveDateTime>,
+    unfinished: usize, //模拟结束时仍未完成的预约数
+}
+
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "分配时间线:")?;
+        for line in self.timeline.iter() {
+            writeln!(f, "  {}", line)?;
+        }
+        writeln!(f, "等待时间:")?;
+        for (email, waits) in self.waits.iter() {
+            let total: i64 = waits.iter().sum();
+            writeln!(
+                f,
+                "  {} 共{}次，平均{}分钟，最长{}分钟",
+                email,
+                waits.len(),
+                total / waits.len() as i64,
+                waits.iter().max().unwrap_or(&0)
+            )?;
+        }
+        let span = match (self.start, self.end) {
+            (Some(start), Some(end)) => end - start,
+            _ => Duration::zero(),
+        };
+        writeln!(f, "利用率(模拟时长{}分钟):", span.num_minutes())?;
+        let ratio = |busy: Duration, devices: i64| {
+            if span > Duration::zero() {
+                busy.num_seconds() as f64 / (span.num_seconds() * devices) as f64 * 100.0
+            } else {
+                0.0
+            }
+        };
+        let mut total = Duration::zero();
+        for index in 0..self.devices {
+            let busy = self
+                .busy
+                .get(&index)
+                .copied()
+                .unwrap_or_else(Duration::zero);
+            total += busy;
+            writeln!(f, "  显卡{} {:.1}%", index, ratio(busy, 1))?;
+        }
+        writeln!(f, "  总计 {:.1}%", ratio(total, self.devices as i64))?;
+        if self.unfinished > 0 {
+            writeln!(f, "模拟超时，仍有{}个预约未完成", self.unfinished)?;
+        }
+        Ok(())
+    }
+}
+
+//以虚拟时钟回放轨迹文件，显卡为假设备：分配后按实际运行时长占用
+#[derive(Debug)]
+pub struct Simulator {
+    trace: String,
+    settings: Option<String>,
+    devices: u32,
+}
+
+impl Simulator {
+    pub fn new(trace: String, settings: Option<String>, devices: u32) -> Simulator {
+        Simulator {
+            trace,
+            settings,
+            devices,
+        }
+    }
+    pub fn run(&self) -> i32 {
+        match self.simulate() {
+            Ok(report) => {
+                print!("{}", report);
+                0
+            }
+            Err(err) => {
+                eprintln!("{}", err);
+                1
+            }
+        }
+    }
+    fn simulate(&self) -> Result<Report, String> {
+        let data = std::fs::read_to_string(&self.trace)
+            .map_err(|_| format!("无法读取轨迹文件: {}", self.trace))?;
+        let mut events: Vec<Event> = Vec::new();
+        for (i, line) in data.lines().enumerate() {
+            if line.trim().is_empty() {
+                continue;
+            }
+            let event = serde_json::from_str(line).map_err(|_| format!("第{}行格式错误", i + 1))?;
+            events.push(event);
+        }
+        let settings = match &self.settings {
+            Some(path) => config::Settings::load_from(path)?,
+            None => config::Settings::load()?,
+        };
+        replay(events, settings, self.devices)
+    }
+}
+
+fn replay(events: Vec<Event>, settings: config::Settings, devices: u32) -> Result<Report, String> {
+    let mut events = events
+        .into_iter()
+        .map(|event| Ok((parse_time(&event.time)?, event)))
+        .collect::<Result<Vec<(NaiveDateTime, Event)>, String>>()?;
+    events.sort_by_key(|(time, _)| *time);
+    let (first, last) = match (events.first(), events.last()) {
+        (Some(first), Some(last)) => (first.0, last.0),
+        _ => return Err(String::from("轨迹文件为空")),
+    };
+
+    let mut info = AppInfo::new();
+    info.settings = settings;
+    info.settings.admins.clear(); //模拟中不需审批
+    info.settings.claim_minutes = 0; //轮到即视为领取
+    info.dry_run = true;
+    let gpus: Vec<nvidia::Nvidia> = (0..devices).map(nvidia::Nvidia::new).collect();
+    let step = Duration::seconds(config::SIMULATE_STEP_SECONDS);
+    let limit = last + Duration::days(config::SIMULATE_MAX_DAYS);
+    let mut runs: BTreeMap<u64, Duration> = BTreeMap::new(); //预约编号到实际运行时长
+    let mut holders: BTreeMap<u32, UserWrapper> = BTreeMap::new();
+    let mut report = Report {
+        devices,
+        start: Some(first),
+        ..Report::default()
+    };
+    let mut next = 0;
+    let mut now = first;
+    loop {
+        while next < events.len() && events[next].0 <= now {
+            let event = &events[next].1;
+            let reply = info.handle(event.to_request()?, None, devices as usize, now);
+            if let (Some(id), Some(run), false) =
+                (reply.id, event.run.or(event.hours), event.finish)
+            {
+                runs.insert(id, Duration::seconds((run * 3600.0) as i64));
+            }
+            next += 1;
+        }
+        //假设备运行满实际时长后由用户注销
+        for holder in info.curr_users.values() {
+            if let (Some(grant_time), Some(run)) = (holder.grant_time(), runs.get(&holder.id)) {
+                if now - grant_time >= *run {
+                    if let Some(x) = info.reservations.get_mut(&holder.id) {
+                        x.finish = true;
+                    }
+                }
+            }
+        }
+        info.check_expiry(now);
+        info.check_preemption(now, devices as usize);
+        info.check_claims(now);
+        info.update_current_user(&gpus, now);
+        //模拟中没有订阅者
+        info.events.clear();
+
+        for (index, holder) in holders.iter() {
+            if info.curr_users.get(index).map(|x| x.id) != Some(holder.id) {
+                let grant_time = holder.grant_time().unwrap_or(now);
+                *report.busy.entry(*index).or_insert_with(Duration::zero) += now - grant_time;
+                report.timeline.push(format!(
+                    "{} 显卡{} 释放 {}(预约{})",
+                    now, index, holder.email, holder.id
+                ));
+            }
+        }
+        for (index, holder) in info.curr_users.iter() {
+            if holders.get(index).map(|x| x.id) != Some(holder.id) {
+                let wait = (now - holder.date_time()).num_minutes().max(0);
+                report
+                    .waits
+                    .entry(holder.email.clone())
+                    .or_default()
+                    .push(wait);
+                report.timeline.push(format!(
+                    "{} 显卡{} 分配 {}(预约{})，等待{}分钟",
+                    now, index, holder.email, holder.id, wait
+                ));
+            }
+        }
+        holders = info.curr_users.clone();
+
+        if next >= events.len() && info.reservations.is_empty() && info.curr_users.is_empty() {
+            break;
+        }
+        if now >= limit {
+            report.unfinished = info.reservations.len();
+            break;
+        }
+        now += step;
+    }
+    report.end = Some(now);
+    Ok(report)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_replay() {
+        let trace = [
+            r#"{"time":"2030-01-01 08:00:00","email":"a@a.com","hours":2}"#,
+            r#"{"time":"2030-01-01 08:30:00","email":"b@b.com","hours":2,"run":1}"#,
+        ];
+        let events: Vec<Event> = trace
+            .iter()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        let report = replay(events, config::Settings::default(), 1).unwrap();
+        assert_eq!(report.waits["a@a.com"], vec![0]);
+        assert_eq!(report.waits["b@b.com"], vec![90]);
+        assert_eq!(
+            report.end.unwrap().to_string(),
+            String::from("2030-01-01 11:00:00")
+        );
+        assert_eq!(report.busy[&0], Duration::hours(3));
+        assert_eq!(report.unfinished, 0);
+    }
+
+    #[test]
+    fn test_settings_errors() {
+        let dir = std::env::temp_dir();
+        let trace = dir.join(format!("RustTip-trace-{}", std::process::id()));
+        let settings = dir.join(format!("RustTip-settings-{}", std::process::id()));
+        std::fs::write(
+            &trace,
+            r#"{"time":"2030-01-01 08:00:00","email":"a@a.com"}"#,
+        )
+        .unwrap();
+        std::fs::write(&settings, "{\"policy\": ").unwrap();
+        let simulator = |path: &std::path::Path| {
+            Simulator::new(
+                trace.to_string_lossy().to_string(),
+                Some(path.to_string_lossy().to_string()),
+                1,
+            )
+        };
+        //显式指定的配置文件缺失或格式错误时报错，不退回默认配置
+        let missing = simulator(&dir.join("RustTip-no-such-settings.json"));
+        assert!(missing.simulate().unwrap_err().contains("无法读取配置文件"));
+        assert!(simulator(&settings)
+            .simulate()
+            .unwrap_err()
+            .contains("格式错误"));
+        std::fs::remove_file(&trace).ok();
+        std::fs::remove_file(&settings).ok();
+    }
+}
@@ -1,10 +1,14 @@
-use super::app::{self, Server, User};
+use super::app::{self, Client, Server, Simulator, User};
+use super::config;
+use super::protocol::{AdminCommand, Query, Request};
+use super::recurrence::Recurrence;
 use super::util::{NaiveDateTimeWrapper, Util, UtilError};
 use chrono::prelude::*;
 use clap::{Arg, SubCommand};
 use std::env;
 use std::process::Command;
 
+#[allow(clippy::enum_variant_names)]
 pub enum CliError {
     UtilError(UtilError),
     InputError,
@@ -18,7 +22,7 @@ impl From<UtilError> for CliError {
 }
 impl std::fmt::Display for CliError {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
-        match &*self {
+        match self {
             CliError::UtilError(err) => match err {
                 UtilError::TimeLateErr => write!(f, "穿越失败"),
                 UtilError::ParseError => write!(f, "时间格式错误"),
@@ -30,24 +34,158 @@ impl std::fmt::Display for CliError {
     }
 }
 
+fn hours_arg() -> Arg<'static, 'static> {
+    Arg::with_name("hours")
+        .long("hours")
+        .takes_value(true)
+        .help("预计使用时长(小时)，到期自动释放 Eg:6")
+}
+
+fn read_hours(sub: &clap::ArgMatches) -> Result<Option<i64>, CliError> {
+    match sub.value_of("hours") {
+        Some(hours) => Ok(Some(Util::check_hours(hours)?)),
+        None => Ok(None),
+    }
+}
+
+fn deadline_arg() -> Arg<'static, 'static> {
+    Arg::with_name("deadline")
+        .long("deadline")
+        .takes_value(true)
+        .requires("hours")
+        .help("任务截止时刻，需同时给出--hours Eg:\"2022-1-1 12:00:00\"")
+}
+
+//时刻格式: 日期 时间
+fn read_date_time(sub: &clap::ArgMatches, name: &str) -> Result<Option<NaiveDateTime>, CliError> {
+    match sub.value_of(name) {
+        Some(date_time) => {
+            let (date, time) = date_time.split_once(' ').ok_or(CliError::InputError)?;
+            Ok(Some(Util::check_date_time(date, time.trim())?))
+        }
+        None => Ok(None),
+    }
+}
+
+fn read_id(sub: &clap::ArgMatches) -> Result<Option<u64>, CliError> {
+    match sub.value_of("id") {
+        Some(id) => Ok(Some(id.parse().map_err(|_| CliError::InputError)?)),
+        None => Ok(None),
+    }
+}
+
 pub fn read_command() -> Result<app::App, CliError> {
     let matches = clap::App::new("RuTip")
         .subcommand(
             SubCommand::with_name("urg")
                 .arg(Arg::with_name("email").required(true))
-                .help("Eg: RustTip urg 邮箱"),
+                .arg(hours_arg())
+                .arg(deadline_arg())
+                .help("Eg: RustTip urg 邮箱 --hours 时长(可选) --deadline 截止时刻(可选)"),
         )
         .subcommand(
             SubCommand::with_name("finish")
                 .arg(Arg::with_name("email").required(true))
-                .help("Eg: RustTip finish 邮箱"),
+                .arg(Arg::with_name("id").help("预约编号，有多个预约时必填"))
+                .help("Eg: RustTip finish 邮箱 预约编号(可选)"),
+        )
+        .subcommand(
+            SubCommand::with_name("modify")
+                .arg(Arg::with_name("email").required(true))
+                .arg(Arg::with_name("id").help("预约编号，有多个预约时必填"))
+                .arg(
+                    Arg::with_name("hours")
+                        .long("hours")
+                        .takes_value(true)
+                        .help("新的使用时长(小时) Eg:6"),
+                )
+                .arg(
+                    Arg::with_name("start")
+                        .long("start")
+                        .takes_value(true)
+                        .help("新的开始时刻 Eg:\"2022-1-1 14:00:00\""),
+                )
+                .group(
+                    clap::ArgGroup::with_name("change")
+                        .args(&["hours", "start"])
+                        .multiple(true)
+                        .required(true),
+                )
+                .help("Eg: RustTip modify 邮箱 预约编号(可选) --hours 新时长 --start 新开始时刻"),
+        )
+        .subcommand(
+            SubCommand::with_name("claim")
+                .arg(Arg::with_name("email").required(true))
+                .arg(Arg::with_name("id").help("预约编号，有多个待领取预约时必填"))
+                .help("Eg: RustTip claim 邮箱 预约编号(可选)"),
         )
         .subcommand(
             SubCommand::with_name("user")
                 .arg(Arg::with_name("email").required(true))
                 .arg(Arg::with_name("date").help("Eg:2022-1-1"))
                 .arg(Arg::with_name("time").help("Eg:14:30:00"))
-                .help("Eg: RustTip user 邮箱 日期(可选) 时间(可选)"),
+                .arg(hours_arg())
+                .arg(
+                    Arg::with_name("early")
+                        .long("early")
+                        .help("允许在开始时刻前提前分配"),
+                )
+                .arg(deadline_arg())
+                .help("Eg: RustTip user 邮箱 日期(可选) 时间(可选) --hours 时长(可选) --early(可选) --deadline 截止时刻(可选)"),
+        )
+        .subcommand(
+            SubCommand::with_name("book")
+                .arg(Arg::with_name("email").required(true))
+                .arg(Arg::with_name("date").required(true).help("Eg:2022-1-1"))
+                .arg(Arg::with_name("time").required(true).help("Eg:14:30:00"))
+                .arg(hours_arg().required(true))
+                .help("Eg: RustTip book 邮箱 日期 时间 --hours 时长"),
+        )
+        .subcommand(
+            SubCommand::with_name("repeat")
+                .arg(Arg::with_name("email").required(true))
+                .arg(Arg::with_name("date").required(true).help("首次日期 Eg:2022-1-1"))
+                .arg(Arg::with_name("time").required(true).help("Eg:14:00:00"))
+                .arg(hours_arg().required(true))
+                .arg(
+                    Arg::with_name("every")
+                        .long("every")
+                        .takes_value(true)
+                        .required(true)
+                        .help("daily / weekly / 指定星期 Eg:tue,thu"),
+                )
+                .arg(
+                    Arg::with_name("until")
+                        .long("until")
+                        .takes_value(true)
+                        .required(true)
+                        .help("截止日期 Eg:2022-6-30"),
+                )
+                .help("Eg: RustTip repeat 邮箱 日期 时间 --hours 时长 --every weekly --until 截止日期"),
+        )
+        .subcommand(
+            SubCommand::with_name("cancel")
+                .arg(Arg::with_name("email").required(true))
+                .arg(Arg::with_name("series").required(true).help("周期预约编号"))
+                .arg(
+                    Arg::with_name("date")
+                        .long("date")
+                        .takes_value(true)
+                        .help("仅取消该日的一次 Eg:2022-1-1"),
+                )
+                .help("Eg: RustTip cancel 邮箱 周期预约编号 --date 日期(可选)"),
+        )
+        .subcommand(
+            SubCommand::with_name("approve")
+                .arg(Arg::with_name("admin").required(true))
+                .arg(Arg::with_name("id").required(true).help("预约编号"))
+                .help("Eg: RustTip approve 管理员邮箱 预约编号"),
+        )
+        .subcommand(
+            SubCommand::with_name("reject")
+                .arg(Arg::with_name("admin").required(true))
+                .arg(Arg::with_name("id").required(true).help("预约编号"))
+                .help("Eg: RustTip reject 管理员邮箱 预约编号"),
         )
         .subcommand(
             SubCommand::with_name("server")
@@ -63,7 +201,57 @@ pub fn read_command() -> Result<app::App, CliError> {
         .subcommand(
             SubCommand::with_name("stop").help("Eg: RustTip stop"),
         )
-        .help("自动预约: RustTip user 邮箱 日期(可选) 时间(可选)\n取消预约: RustTip finish 邮箱\n紧急预约: RustTip urg 邮箱\n服务启动: RustTip server 邮箱 SMTP服务密码\n服务关闭: RustTip stop")
+        .subcommand(
+            SubCommand::with_name("release")
+                .arg(Arg::with_name("id").required(true).help("预约编号"))
+                .help("Eg: RustTip release 预约编号"),
+        )
+        .subcommand(
+            SubCommand::with_name("reorder")
+                .arg(Arg::with_name("id").required(true).help("预约编号"))
+                .arg(Arg::with_name("position").required(true).help("排队位置，从1开始"))
+                .help("Eg: RustTip reorder 预约编号 排队位置"),
+        )
+        .subcommand(
+            SubCommand::with_name("issue-token")
+                .arg(Arg::with_name("email").required(true))
+                .help("Eg: RustTip issue-token 邮箱"),
+        )
+        .subcommand(
+            SubCommand::with_name("revoke-token")
+                .arg(Arg::with_name("email").required(true))
+                .help("Eg: RustTip revoke-token 邮箱"),
+        )
+        .subcommand(SubCommand::with_name("status").help("Eg: RustTip status"))
+        .subcommand(SubCommand::with_name("queue").help("Eg: RustTip queue"))
+        .subcommand(
+            SubCommand::with_name("whoami")
+                .arg(Arg::with_name("email").required(true))
+                .help("Eg: RustTip whoami 邮箱"),
+        )
+        .subcommand(
+            SubCommand::with_name("watch")
+                .arg(Arg::with_name("email").help("只显示该用户预约的事件"))
+                .help("Eg: RustTip watch 邮箱(可选)"),
+        )
+        .subcommand(
+            SubCommand::with_name("simulate")
+                .arg(Arg::with_name("trace").required(true).help("轨迹文件，每行一个JSON记录"))
+                .arg(
+                    Arg::with_name("settings")
+                        .long("settings")
+                        .takes_value(true)
+                        .help("使用指定的配置文件，缺省为settings.json"),
+                )
+                .arg(
+                    Arg::with_name("gpus")
+                        .long("gpus")
+                        .takes_value(true)
+                        .help("模拟的显卡数，默认1"),
+                )
+                .help("Eg: RustTip simulate 轨迹文件 --settings 配置文件(可选) --gpus 显卡数(可选)"),
+        )
+        .help("自动预约: RustTip user 邮箱 日期(可选) 时间(可选) --hours 时长(可选)\n时段预约: RustTip book 邮箱 日期 时间 --hours 时长\n周期预约: RustTip repeat 邮箱 日期 时间 --hours 时长 --every 规则 --until 截止日期\n取消周期: RustTip cancel 邮箱 周期预约编号 --date 日期(可选)\n修改预约: RustTip modify 邮箱 预约编号(可选) --hours 新时长 --start 新开始时刻\n领取显卡: RustTip claim 邮箱 预约编号(可选)\n取消预约: RustTip finish 邮箱 预约编号(可选)\n预约审批: RustTip approve/reject 管理员邮箱 预约编号\n紧急预约: RustTip urg 邮箱\n服务启动: RustTip server 邮箱 SMTP服务密码\n服务状态: RustTip status\n排队列表: RustTip queue\n我的预约: RustTip whoami 邮箱\n服务关闭: RustTip stop\n策略模拟: RustTip simulate 轨迹文件 --settings 配置文件(可选) --gpus 显卡数(可选)")
         .get_matches();
 
     match matches.subcommand() {
@@ -89,14 +277,84 @@ pub fn read_command() -> Result<app::App, CliError> {
                 let date = Util::check_date(sub.value_of("date").unwrap())?;
                 info.1 = Some(date);
             }
-            return Ok(app::App::User(User::new(
-                info.0, info.1, info.2, false, false,
-            )));
+            let user = User::new(info.0, info.1, info.2, false)
+                .with_duration(read_hours(sub)?)
+                .with_early(sub.is_present("early"))
+                .with_deadline(read_date_time(sub, "deadline")?);
+            return Ok(client(Request::Book(user.into())));
+        }
+        ("book", Some(sub)) => {
+            Util::check_email(sub.value_of("email").unwrap())?;
+            let datetime = Util::check_date_time(
+                sub.value_of("date").unwrap(),
+                sub.value_of("time").unwrap(),
+            )?;
+            let user = User::new(
+                sub.value_of("email").unwrap().to_string(),
+                Some(datetime.date()),
+                Some(datetime.time()),
+                false,
+            )
+            .with_duration(read_hours(sub)?)
+            .with_slot(true);
+            return Ok(client(Request::Book(user.into())));
+        }
+        ("repeat", Some(sub)) => {
+            Util::check_email(sub.value_of("email").unwrap())?;
+            let datetime = Util::check_date_time(
+                sub.value_of("date").unwrap(),
+                sub.value_of("time").unwrap(),
+            )?;
+            let until = Util::check_date(sub.value_of("until").unwrap())?;
+            let rule =
+                Recurrence::parse(sub.value_of("every").unwrap()).ok_or(CliError::InputError)?;
+            let user = User::new(
+                sub.value_of("email").unwrap().to_string(),
+                Some(datetime.date()),
+                Some(datetime.time()),
+                false,
+            )
+            .with_duration(read_hours(sub)?)
+            .with_slot(true);
+            return Ok(client(Request::Repeat {
+                booking: user.into(),
+                rule,
+                until: until.to_string(),
+            }));
+        }
+        ("cancel", Some(sub)) => {
+            Util::check_email(sub.value_of("email").unwrap())?;
+            let series: u64 = sub
+                .value_of("series")
+                .unwrap()
+                .parse()
+                .map_err(|_| CliError::InputError)?;
+            let date = match sub.value_of("date") {
+                Some(date) => Some(Util::check_date(date)?.to_string()),
+                None => None,
+            };
+            return Ok(client(Request::Cancel {
+                email: sub.value_of("email").unwrap().to_string(),
+                series,
+                date,
+            }));
+        }
+        (cmd @ ("approve" | "reject"), Some(sub)) => {
+            Util::check_email(sub.value_of("admin").unwrap())?;
+            let id = read_id(sub)?.ok_or(CliError::InputError)?;
+            let admin = sub.value_of("admin").unwrap().to_string();
+            let command = if cmd == "approve" {
+                AdminCommand::Approve { admin, id }
+            } else {
+                AdminCommand::Reject { admin, id }
+            };
+            return Ok(self::admin(command));
         }
         ("server", Some(_)) => {
             //启动子进程，参数全部传递给子进程
             let mut args: Vec<String> = env::args().collect();
             args[1] = String::from("subserver");
+            #[allow(clippy::zombie_processes)]
             Command::new(&args[0])
                 .args(&args[1..])
                 .spawn()
@@ -118,13 +376,10 @@ pub fn read_command() -> Result<app::App, CliError> {
         ("urg", Some(sub)) => {
             if sub.value_of("email").is_some() {
                 Util::check_email(sub.value_of("email").unwrap())?;
-                return Ok(app::App::User(User::new(
-                    sub.value_of("email").unwrap().to_string(),
-                    None,
-                    None,
-                    true,
-                    false,
-                )));
+                let user = User::new(sub.value_of("email").unwrap().to_string(), None, None, true)
+                    .with_duration(read_hours(sub)?)
+                    .with_deadline(read_date_time(sub, "deadline")?);
+                return Ok(client(Request::Urgent(user.into())));
             } else {
                 Err(CliError::InputError)?;
             }
@@ -132,32 +387,119 @@ pub fn read_command() -> Result<app::App, CliError> {
         ("finish", Some(sub)) => {
             if sub.value_of("email").is_some() {
                 Util::check_email(sub.value_of("email").unwrap())?;
-                return Ok(app::App::User(User::new(
-                    sub.value_of("email").unwrap().to_string(),
-                    None,
-                    None,
-                    false,
-                    true,
-                )));
+                return Ok(client(Request::Finish {
+                    email: sub.value_of("email").unwrap().to_string(),
+                    id: read_id(sub)?,
+                }));
             } else {
                 Err(CliError::InputError)?;
             }
         }
-        ("stop", Some(_))=> {
-            return Ok(app::App::User(User::new(
-                String::from("stop@stop.stop"),
-                None,
-                None,
-                false,
-                true,
+        ("modify", Some(sub)) => {
+            Util::check_email(sub.value_of("email").unwrap())?;
+            let start = read_date_time(sub, "start")?;
+            return Ok(client(Request::Modify {
+                email: sub.value_of("email").unwrap().to_string(),
+                id: read_id(sub)?,
+                duration: read_hours(sub)?,
+                start: start.map(|start| start.to_string()),
+            }));
+        }
+        ("claim", Some(sub)) => {
+            Util::check_email(sub.value_of("email").unwrap())?;
+            return Ok(client(Request::Claim {
+                email: sub.value_of("email").unwrap().to_string(),
+                id: read_id(sub)?,
+            }));
+        }
+        ("simulate", Some(sub)) => {
+            let devices: u32 = match sub.value_of("gpus") {
+                Some(gpus) => gpus.parse().map_err(|_| CliError::InputError)?,
+                None => 1,
+            };
+            return Ok(app::App::Simulate(Simulator::new(
+                sub.value_of("trace").unwrap().to_string(),
+                sub.value_of("settings").map(|path| path.to_string()),
+                devices,
             )));
         }
+        (cmd @ ("status" | "queue"), Some(_)) => {
+            let query = if cmd == "status" {
+                Query::Status
+            } else {
+                Query::Queue
+            };
+            return Ok(client(Request::Query {
+                email: String::new(),
+                query,
+            }));
+        }
+        ("watch", Some(sub)) => {
+            let email = match sub.value_of("email") {
+                Some(email) => {
+                    Util::check_email(email)?;
+                    Some(email.to_string())
+                }
+                None => None,
+            };
+            return Ok(client(Request::Watch { email }));
+        }
+        ("whoami", Some(sub)) => {
+            Util::check_email(sub.value_of("email").unwrap())?;
+            return Ok(client(Request::Query {
+                email: sub.value_of("email").unwrap().to_string(),
+                query: Query::Whoami,
+            }));
+        }
+        ("stop", Some(_)) => {
+            return Ok(admin(AdminCommand::Stop));
+        }
+        ("release", Some(sub)) => {
+            let id = read_id(sub)?.ok_or(CliError::InputError)?;
+            return Ok(admin(AdminCommand::Release { id }));
+        }
+        ("reorder", Some(sub)) => {
+            let id = read_id(sub)?.ok_or(CliError::InputError)?;
+            let position: usize = sub
+                .value_of("position")
+                .unwrap()
+                .parse()
+                .map_err(|_| CliError::InputError)?;
+            return Ok(admin(AdminCommand::Reorder { id, position }));
+        }
+        ("issue-token", Some(sub)) => {
+            let email = sub.value_of("email").unwrap();
+            Util::check_email(email)?;
+            return Ok(admin(AdminCommand::IssueToken {
+                email: email.to_string(),
+            }));
+        }
+        ("revoke-token", Some(sub)) => {
+            let email = sub.value_of("email").unwrap();
+            Util::check_email(email)?;
+            return Ok(admin(AdminCommand::RevokeToken {
+                email: email.to_string(),
+            }));
+        }
         _ => Err(CliError::InputError)?,
     }
 
     Err(CliError::InputError)
 }
 
+fn client(request: Request) -> app::App {
+    app::App::Client(Client::new(request))
+}
+
+//管理操作附带管理员令牌，令牌文件不可读时仅依靠uid鉴权
+fn admin(command: AdminCommand) -> app::App {
+    let path = env::var("RUSTTIP_ADMIN_TOKEN_FILE").unwrap_or(config::ADMIN_TOKEN_FILE.to_string());
+    let token = std::fs::read_to_string(path)
+        .ok()
+        .map(|token| token.trim().to_string());
+    client(Request::Admin { token, command })
+}
+
 #[cfg(test)]
 mod tests {
     #[test]
@@ -1,6 +1,26 @@
-pub const INFO_FILE: &'static str = "info.json";
-pub const SERVER: &'static str = "smtp.qq.com";
-pub const TCP_ADDR: &'static str = "127.0.0.1:7630";
+use serde::{Deserialize, Serialize};
+
+pub const INFO_FILE: &str = "info.json";
+pub const SERVER: &str = "smtp.qq.com";
+pub const MAIL_RETRIES: u32 = 5; //邮件发送失败后的重试次数
+pub const MAIL_RETRY_SECONDS: u64 = 5; //首次重试的等待时长，之后每次加倍
+pub const TCP_ADDR: &str = "127.0.0.1:7630";
+pub const REPLY_TIMEOUT_SECONDS: u64 = 10; //客户端等待应答的时限
+pub const SOCKET_PATH: &str = "/run/RustTip/RustTip.sock"; //所在目录须仅服务端用户可写，不存在时自动创建
+pub const SOCKET_MODE: &str = "666"; //八进制权限
+pub const SETTINGS_FILE: &str = "settings.json";
+pub const HTTP_MAX_HEADER: usize = 8 * 1024; //HTTP请求头上限(字节)
+pub const HTTP_MAX_BODY: usize = 64 * 1024; //HTTP请求体上限(字节)
+pub const MAX_MESSAGE_BYTES: usize = 64 * 1024; //一行请求的上限(字节)
+pub const MAX_CONNECTIONS: usize = 64; //本机、HTTP与远程连接各自同时处理的上限，订阅连接单独按同一上限计
+pub const SUBSCRIBER_QUEUE: usize = 256; //订阅者待推送的事件上限，积压超过时断开
+pub const MAX_DURATION_MINUTES: i64 = 366 * 24 * 60; //预约时长上限
+pub const MAX_YEAR: i32 = 9999; //请求中时刻的年份上限
+pub const CLIENT_TOKEN_FILE: &str = ".rusttip_token"; //客户端API令牌，位于用户主目录
+pub const API_TOKEN_BYTES: usize = 24; //API令牌的随机字节数
+pub const TLS_CERT: &str = "cert.pem";
+pub const TLS_KEY: &str = "key.pem";
+pub const ADMIN_TOKEN_FILE: &str = "/etc/RustTip/admin_token"; //仅所有者可读写
 
 pub const DEVICE_FREE: u32 = 5;
 pub const DEVICE_LOW_EFFICIENCY: u32 = 5;
@@ -8,3 +28,141 @@ pub const DEVICE_LOW_EFFICIENCY: u32 = 5;
 pub const TIME_GAP_SECONDS: i64 = 10;
 pub const TIME_GAP_MAX_MINUTES: i64 = 60;
 
+pub const EXPIRY_NOTICE_MINUTES: i64 = 15;
+pub const LOOKAHEAD_MINUTES: i64 = 600;
+pub const HALF_LIFE_HOURS: f64 = 168.0;
+pub const FAIR_SHARE_WEIGHT: f64 = 1.0;
+pub const APPROVAL_MAX_MINUTES: i64 = 1440;
+pub const PREEMPT_GRACE_MINUTES: i64 = 30;
+pub const IDLE_RELEASE_MINUTES: i64 = 60;
+pub const CLAIM_MINUTES: i64 = 15; //轮到用户后确认领取的时限
+pub const MAX_NO_SHOWS: u32 = 3; //连续未领取次数达到后注销预约
+pub const SIMULATE_STEP_SECONDS: i64 = 60; //模拟的虚拟时钟步长
+pub const SIMULATE_MAX_DAYS: i64 = 30; //最后一条记录后最多继续模拟的天数
+pub const HISTORY_STEP_SECONDS: i64 = 30; //面板曲线的采样间隔
+pub const HISTORY_SAMPLES: usize = 240; //面板曲线保留的采样数
+pub const MAX_RELEASES: usize = 500; //info.json保留的强制释放记录数
+pub const MAX_DENIALS: usize = 500; //info.json保留的拒绝记录数
+pub const MAX_OCCURRENCES: usize = 366;
+
+//排队策略：fair按紧急程度与公平份额，edf按截止时刻的松弛时间
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Policy {
+    #[default]
+    Fair,
+    Edf,
+}
+
+//服务端可调参数，从SETTINGS_FILE读取，缺省项使用默认值
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(default)]
+pub struct Settings {
+    pub lookahead_minutes: i64,      //允许提前的用户可在开始前多久分配
+    pub half_life_hours: f64,        //历史用量衰减半衰期
+    pub fair_share_weight: f64,      //每GPU小时用量抵消的等待小时数
+    pub admins: Vec<String>,         //管理员邮箱，为空时不启用审批
+    pub approval_max_minutes: i64,   //超过该时长的预约需要审批
+    pub preempt: bool,               //紧急预约是否抢占普通持有者
+    pub preempt_grace_minutes: i64,  //抢占警告到收回显卡的宽限期
+    pub idle_release_minutes: i64,   //空闲通知后持续空闲多久强制释放，0为不释放
+    pub policy: Policy,              //排队策略
+    pub claim_minutes: i64,          //轮到用户后确认领取的时限，0为直接分配
+    pub socket_path: String,         //本地套接字路径
+    pub socket_mode: String,         //本地套接字的八进制权限
+    pub admin_uids: Vec<u32>,        //可执行管理操作的本地用户uid
+    pub admin_token_file: String,    //管理员令牌文件，权限过宽时不采信
+    pub http_addr: Option<String>,   //HTTP接口监听地址，为空时不启用
+    pub remote_addr: Option<String>, //远程客户端的TLS监听地址，为空时不启用
+    pub tls_cert: String,            //PEM格式的证书链
+    pub tls_key: String,             //PEM格式的PKCS#8私钥
+}
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            lookahead_minutes: LOOKAHEAD_MINUTES,
+            half_life_hours: HALF_LIFE_HOURS,
+            fair_share_weight: FAIR_SHARE_WEIGHT,
+            admins: Vec::new(),
+            approval_max_minutes: APPROVAL_MAX_MINUTES,
+            preempt: false,
+            preempt_grace_minutes: PREEMPT_GRACE_MINUTES,
+            idle_release_minutes: IDLE_RELEASE_MINUTES,
+            policy: Policy::Fair,
+            claim_minutes: CLAIM_MINUTES,
+            socket_path: SOCKET_PATH.to_string(),
+            socket_mode: SOCKET_MODE.to_string(),
+            admin_uids: Vec::new(),
+            admin_token_file: ADMIN_TOKEN_FILE.to_string(),
+            http_addr: None,
+            remote_addr: None,
+            tls_cert: TLS_CERT.to_string(),
+            tls_key: TLS_KEY.to_string(),
+        }
+    }
+}
+impl Settings {
+    //启动目录下的settings.json可选，不存在时使用默认值
+    pub fn load() -> Result<Settings, String> {
+        match std::fs::read_to_string(SETTINGS_FILE) {
+            Ok(data) => Settings::parse(&data, SETTINGS_FILE),
+            Err(_) => Ok(Settings::default()),
+        }
+    }
+    //显式指定的配置文件必须存在
+    pub fn load_from(path: &str) -> Result<Settings, String> {
+        let data =
+            std::fs::read_to_string(path).map_err(|_| format!("无法读取配置文件: {}", path))?;
+        Settings::parse(&data, path)
+    }
+    fn parse(data: &str, path: &str) -> Result<Settings, String> {
+        let settings: Settings = serde_json::from_str(data)
+            .map_err(|err| format!("配置文件{}格式错误: {}", path, err))?;
+        settings
+            .validate()
+            .map_err(|err| format!("配置文件{}取值错误: {}", path, err))?;
+        Ok(settings)
+    }
+    //分钟数参与时刻加减，过大时溢出panic；半衰期非正时优先级为NaN
+    fn validate(&self) -> Result<(), String> {
+        for (name, minutes) in [
+            ("lookahead_minutes", self.lookahead_minutes),
+            ("approval_max_minutes", self.approval_max_minutes),
+            ("preempt_grace_minutes", self.preempt_grace_minutes),
+            ("idle_release_minutes", self.idle_release_minutes),
+            ("claim_minutes", self.claim_minutes),
+        ] {
+            if !(0..=MAX_DURATION_MINUTES).contains(&minutes) {
+                return Err(format!("{}须在0到{}之间", name, MAX_DURATION_MINUTES));
+            }
+        }
+        if !(self.half_life_hours.is_finite() && self.half_life_hours > 0.0) {
+            return Err(String::from("half_life_hours须为正数"));
+        }
+        if !(self.fair_share_weight.is_finite() && self.fair_share_weight >= 0.0) {
+            return Err(String::from("fair_share_weight须为非负数"));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_ranges() {
+        assert!(Settings::parse("{}", SETTINGS_FILE).is_ok());
+        assert!(Settings::parse(r#"{"claim_minutes":0}"#, SETTINGS_FILE).is_ok());
+        for data in [
+            r#"{"preempt_grace_minutes":100000000000000}"#,
+            r#"{"claim_minutes":-1}"#,
+            r#"{"lookahead_minutes":9223372036854775807}"#,
+            r#"{"half_life_hours":0}"#,
+            r#"{"half_life_hours":-1.5}"#,
+            r#"{"fair_share_weight":-1}"#,
+        ] {
+            let err = Settings::parse(data, SETTINGS_FILE).unwrap_err();
+            assert!(err.contains("取值错误"), "{}", data);
+        }
+    }
+}
@@ -0,0 +1,307 @@
+use super::app::User;
+use super::config;
+use super::protocol::{Booking, ErrorCode, Query, Reply, Request};
+use super::util::Util;
+use serde::Deserialize;
+use std::io::prelude::*;
+
+//接口描述，GET /api/openapi.json 返回
+pub const SCHEMA: &str = include_str!("../../openapi.json");
+//只读状态面板，页面定时请求/api/dashboard刷新
+pub const DASHBOARD: &str = include_str!("../../dashboard.html");
+
+//一条HTTP请求，只保留路由需要的部分
+#[derive(Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: String,
+    pub path: String,
+    pub body: String,
+    //Authorization: Bearer 头携带的API令牌
+    pub token: Option<String>,
+}
+
+//路由结果：转交主循环的请求，或直接返回的静态内容
+#[derive(Debug, PartialEq)]
+pub enum Route {
+    Request(Request),
+    Schema,
+    Dashboard,
+}
+
+//POST /api/book 与 /api/urgent 的请求体，时刻格式为"%Y-%m-%d %H:%M:%S"
+#[derive(Deserialize, Debug)]
+struct BookBody {
+    email: String,
+    #[serde(default)]
+    start: Option<String>,
+    #[serde(default)]
+    hours: Option<f64>,
+    #[serde(default)]
+    slot: bool,
+    #[serde(default)]
+    early: bool,
+    #[serde(default)]
+    deadline: Option<String>,
+}
+
+//POST /api/finish 的请求体
+#[derive(Deserialize, Debug)]
+struct FinishBody {
+    email: String,
+    #[serde(default)]
+    id: Option<u64>,
+}
+
+fn bad_request(message: &str) -> Reply {
+    Reply::error(ErrorCode::BadRequest, message.to_string())
+}
+
+impl HttpRequest {
+    //读取请求行、请求头与Content-Length指定长度的请求体，请求行与请求头合计不超过HTTP_MAX_HEADER
+    pub fn read<R: BufRead>(reader: &mut R) -> Result<HttpRequest, Reply> {
+        let mut line = String::new();
+        let mut header_bytes = reader
+            .take(config::HTTP_MAX_HEADER as u64)
+            .read_line(&mut line)
+            .map_err(|_| bad_request("请求读取失败！"))?;
+        if !line.ends_with('\n') {
+            return Err(bad_request("请求行过长或不完整！"));
+        }
+        let mut parts = line.split_whitespace();
+        let (method, path) = match (parts.next(), parts.next()) {
+            (Some(method), Some(path)) => (method.to_string(), path.to_string()),
+            _ => return Err(bad_request("请求行格式错误！")),
+        };
+        let mut length: usize = 0;
+        let mut token = None;
+        loop {
+            let mut header = String::new();
+            let n = reader
+                .take((config::HTTP_MAX_HEADER + 1 - header_bytes) as u64)
+                .read_line(&mut header)
+                .map_err(|_| bad_request("请求读取失败！"))?;
+            header_bytes += n;
+            if header_bytes > config::HTTP_MAX_HEADER {
+                return Err(bad_request("请求头过长！"));
+            }
+            let header = header.trim_end();
+            if n == 0 || header.is_empty() {
+                break;
+            }
+            if let Some((name, value)) = header.split_once(':') {
+                if name.trim().eq_ignore_ascii_case("content-length") {
+                    length = value
+                        .trim()
+                        .parse()
+                        .map_err(|_| bad_request("Content-Length格式错误！"))?;
+                } else if name.trim().eq_ignore_ascii_case("authorization") {
+                    token = value
+                        .trim()
+                        .strip_prefix("Bearer ")
+                        .map(|x| x.trim().to_string());
+                }
+            }
+        }
+        if length > config::HTTP_MAX_BODY {
+            return Err(bad_request("请求体过长！"));
+        }
+        let mut body = vec![0; length];
+        reader
+            .read_exact(&mut body)
+            .map_err(|_| bad_request("请求体不完整！"))?;
+        let body = String::from_utf8(body).map_err(|_| bad_request("请求体不是UTF-8编码！"))?;
+        Ok(HttpRequest {
+            method,
+            path,
+            body,
+            token,
+        })
+    }
+
+    pub fn route(&self) -> Result<Route, Reply> {
+        //忽略查询参数
+        let path = self.path.split('?').next().unwrap_or("");
+        match (self.method.as_str(), path) {
+            ("GET", "/" | "/dashboard") => Ok(Route::Dashboard),
+            ("GET", "/api/openapi.json") => Ok(Route::Schema),
+            ("GET", "/api/dashboard") => Ok(Route::Request(Request::Query {
+                email: String::new(),
+                query: Query::Dashboard,
+            })),
+            ("GET", "/api/status") => Ok(Route::Request(Request::Query {
+                email: String::new(),
+                query: Query::Status,
+            })),
+            ("GET", "/api/queue") => Ok(Route::Request(Request::Query {
+                email: String::new(),
+                query: Query::Queue,
+            })),
+            ("POST", "/api/book") => Ok(Route::Request(Request::Book(self.booking(false)?))),
+            ("POST", "/api/urgent") => Ok(Route::Request(Request::Urgent(self.booking(true)?))),
+            ("POST", "/api/finish") => {
+                let body: FinishBody = serde_json::from_str(&self.body)
+                    .map_err(|_| bad_request("请求体格式错误！"))?;
+                Util::check_email(&body.email).map_err(|_| bad_request("邮箱格式错误！"))?;
+                Ok(Route::Request(Request::Finish {
+                    email: body.email,
+                    id: body.id,
+                }))
+            }
+            _ => Err(Reply::error(
+                ErrorCode::NotFound,
+                format!("不支持的接口: {} {}", self.method, path),
+            )),
+        }
+    }
+
+    //与命令行相同的校验，时段预约需要开始时刻与时长
+    fn booking(&self, urg: bool) -> Result<Booking, Reply> {
+        let body: BookBody =
+            serde_json::from_str(&self.body).map_err(|_| bad_request("请求体格式错误！"))?;
+        Util::check_email(&body.email).map_err(|_| bad_request("邮箱格式错误！"))?;
+        let parse = |time: &str| {
+            let (date, time) = time.split_once(' ').unwrap_or((time, ""));
+            Util::check_date_time(date, time).map_err(|_| bad_request("时刻格式错误或已过去！"))
+        };
+        let start = match &body.start {
+            Some(start) => Some(parse(start)?),
+            None => None,
+        };
+        let deadline = match &body.deadline {
+            Some(deadline) => Some(parse(deadline)?),
+            None => None,
+        };
+        let duration = match body.hours.map(|hours| (hours * 60.0).round()) {
+            Some(minutes) if minutes.is_finite() && minutes > 0.0 => Some(minutes as i64),
+            Some(_) => return Err(bad_request("时长必须大于0！")),
+            None => None,
+        };
+        if body.slot && (start.is_none() || duration.is_none()) {
+            return Err(bad_request("时段预约需要指定start与hours！"));
+        }
+        if deadline.is_some() && duration.is_none() {
+            return Err(bad_request("指定deadline时需要指定hours！"));
+        }
+        let user = User::new(
+            body.email,
+            start.map(|start| start.date()),
+            start.map(|start| start.time()),
+            urg,
+        )
+        .with_duration(duration)
+        .with_slot(body.slot)
+        .with_early(body.early)
+        .with_deadline(deadline);
+        Ok(user.into())
+    }
+}
+
+//应答序列化为JSON响应体，状态码由错误码决定
+pub fn response(reply: &Reply) -> Vec<u8> {
+    let status = reply.code.map_or(200, |code| code.http_status());
+    write_response(status, JSON, &serde_json::to_string(reply).unwrap())
+}
+
+pub const JSON: &str = "application/json; charset=utf-8";
+pub const HTML: &str = "text/html; charset=utf-8";
+
+pub fn write_response(status: u16, content_type: &str, body: &str) -> Vec<u8> {
+    let reason = match status {
+        200 => "OK",
+        400 => "Bad Request",
+        403 => "Forbidden",
+        404 => "Not Found",
+        409 => "Conflict",
+        422 => "Unprocessable Entity",
+        _ => "Service Unavailable",
+    };
+    format!(
+        "HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        reason,
+        content_type,
+        body.len(),
+        body
+    )
+    .into_bytes()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    fn read(head: &str, body: &str) -> HttpRequest {
+        let raw = format!("{}\r\nContent-Length: {}\r\n\r\n{}", head, body.len(), body);
+        HttpRequest::read(&mut raw.as_bytes()).unwrap()
+    }
+    #[test]
+    fn test_route() {
+        let request = read("POST /api/finish HTTP/1.1", r#"{"email":"a@a.com","id":3}"#);
+        assert_eq!(
+            request.route().unwrap(),
+            Route::Request(Request::Finish {
+                email: String::from("a@a.com"),
+                id: Some(3)
+            })
+        );
+        let request = read("GET /api/queue?x=1 HTTP/1.1", "");
+        assert!(matches!(request.route(), Ok(Route::Request(_))));
+        let request = read(
+            "POST /api/book HTTP/1.1",
+            r#"{"email":"a@a.com","slot":true}"#,
+        );
+        assert_eq!(
+            request.route().unwrap_err().code,
+            Some(ErrorCode::BadRequest)
+        );
+        let request = read("DELETE /api/queue HTTP/1.1", "");
+        assert_eq!(request.route().unwrap_err().code, Some(ErrorCode::NotFound));
+    }
+    #[test]
+    fn test_bearer_token() {
+        let request = read("GET /api/status HTTP/1.1", "");
+        assert_eq!(request.token, None);
+        let raw = "POST /api/finish HTTP/1.1\r\nauthorization: Bearer abc \r\n\r\n";
+        let request = HttpRequest::read(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.token, Some(String::from("abc")));
+        let raw = "POST /api/finish HTTP/1.1\r\nAuthorization: Basic abc\r\n\r\n";
+        let request = HttpRequest::read(&mut raw.as_bytes()).unwrap();
+        assert_eq!(request.token, None);
+    }
+    #[test]
+    fn test_read_limits() {
+        let long_line = format!(
+            "GET /{} HTTP/1.1\r\n\r\n",
+            "x".repeat(config::HTTP_MAX_HEADER)
+        );
+        assert!(HttpRequest::read(&mut long_line.as_bytes()).is_err());
+        let long_header = format!(
+            "GET / HTTP/1.1\r\nX: {}\r\n\r\n",
+            "x".repeat(config::HTTP_MAX_HEADER)
+        );
+        assert!(HttpRequest::read(&mut long_header.as_bytes()).is_err());
+        let raw = format!(
+            "POST / HTTP/1.1\r\nContent-Length: {}\r\n\r\n",
+            config::HTTP_MAX_BODY + 1
+        );
+        assert!(HttpRequest::read(&mut raw.as_bytes()).is_err());
+        assert!(HttpRequest::read(&mut "GET / HTTP/1.1\r\n\r\n".as_bytes()).is_ok());
+    }
+    #[test]
+    fn test_schema() {
+        let schema: serde_json::Value = serde_json::from_str(SCHEMA).unwrap();
+        for path in [
+            "/api/status",
+            "/api/queue",
+            "/api/dashboard",
+            "/api/book",
+            "/api/urgent",
+            "/api/finish",
+        ] {
+            assert!(schema["paths"].get(path).is_some(), "{}", path);
+        }
+        let response = response(&Reply::error(ErrorCode::Conflict, String::from("冲突")));
+        assert!(String::from_utf8(response)
+            .unwrap()
+            .starts_with("HTTP/1.1 409 Conflict\r\n"));
+    }
+}
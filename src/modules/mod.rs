@@ -1,5 +1,8 @@
 pub mod app;
 pub mod cli;
 pub mod config;
+pub mod http;
 pub mod nvidia;
+pub mod protocol;
+pub mod recurrence;
 pub mod util;
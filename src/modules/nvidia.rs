@@ -1,18 +1,36 @@
 use super::config;
-use regex::Regex;
+use lazy_static::lazy_static;
+use serde::{Deserialize, Serialize};
 use std::process::Command;
+use std::sync::Mutex;
+
+lazy_static! {
+    //nvidia-smi上次是否调用失败，只在失败与恢复时记录日志，避免每秒刷屏
+    static ref QUERY_FAILED: Mutex<bool> = Mutex::new(false);
+}
+
+//一张显卡的读数快照
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reading {
+    pub index: u32,
+    pub use_ratio: u8,
+    pub used_memory: u32,
+    pub total_memory: u32,
+}
 
 #[derive(Debug)]
 pub struct Nvidia {
-    used_memory: u16,
-    total_memory: u16,
+    index: u32,
+    used_memory: u32,
+    total_memory: u32,
     use_ratio: u8,
     counter_free: u32,
     counter_efficiency: u32,
 }
 impl Nvidia {
-    pub fn new() -> Nvidia {
+    pub fn new(index: u32) -> Nvidia {
         Nvidia {
+            index,
             used_memory: 0,
             total_memory: 0,
             use_ratio: 0,
@@ -20,42 +38,99 @@ impl Nvidia {
             counter_efficiency: 0,
         }
     }
-    pub fn read_from_terminal(&mut self) {
+    #[cfg(test)]
+    pub fn with_reading(index: u32, use_ratio: u8, used_memory: u32, total_memory: u32) -> Nvidia {
+        Nvidia {
+            used_memory,
+            total_memory,
+            use_ratio,
+            ..Nvidia::new(index)
+        }
+    }
+    pub fn index(&self) -> u32 {
+        self.index
+    }
+    pub fn reading(&self) -> Reading {
+        Reading {
+            index: self.index,
+            use_ratio: self.use_ratio,
+            used_memory: self.used_memory,
+            total_memory: self.total_memory,
+        }
+    }
+    //枚举本机所有显卡
+    pub fn scan() -> Vec<Nvidia> {
+        let mut gpus: Vec<Nvidia> = Nvidia::query()
+            .iter()
+            .map(|line| Nvidia::new(line.0))
+            .collect();
+        Nvidia::read_from_terminal(&mut gpus);
+        gpus
+    }
+    //一次调用读取所有显卡状态
+    pub fn read_from_terminal(gpus: &mut [Nvidia]) {
+        for (index, use_ratio, used_memory, total_memory) in Nvidia::query() {
+            if let Some(gpu) = gpus.iter_mut().find(|gpu| gpu.index == index) {
+                gpu.use_ratio = use_ratio;
+                gpu.used_memory = used_memory;
+                gpu.total_memory = total_memory;
+            }
+        }
+    }
+    //nvidia-smi不存在或执行失败时返回空列表，服务端照常运行
+    fn query() -> Vec<(u32, u8, u32, u32)> {
         let output = Command::new("nvidia-smi")
-            .output()
-            .expect("命令执行异常错误提示");
-        let message = String::from_utf8(output.stdout).unwrap();
-        let re = Regex::new(r"\d{1,3}%").unwrap();
-        let caps = re.captures(&message).unwrap();
-        self.use_ratio = caps
-            .get(0)
-            .map_or("", |m| m.as_str())
-            .replace("%", "")
-            .parse()
-            .unwrap();
-
-        let re = Regex::new(r"\d{1,5}MiB").unwrap();
-        let mut caps = re.captures_iter(&message).into_iter();
-
-        self.used_memory = caps
-            .next()
-            .unwrap()
-            .get(0)
-            .map_or("", |m| m.as_str())
-            .replace("MiB", "")
-            .parse()
-            .unwrap();
-        self.total_memory = caps
-            .next()
-            .unwrap()
-            .get(0)
-            .map_or("", |m| m.as_str())
-            .replace("MiB", "")
-            .parse()
-            .unwrap();
+            .args([
+                "--query-gpu=index,utilization.gpu,memory.used,memory.total",
+                "--format=csv,noheader,nounits",
+            ])
+            .output();
+        let mut failed = QUERY_FAILED.lock().unwrap();
+        let output = match output {
+            Ok(output) if output.status.success() => output,
+            Ok(output) => {
+                if !*failed {
+                    eprintln!("nvidia-smi执行失败: {}", output.status);
+                }
+                *failed = true;
+                return Vec::new();
+            }
+            Err(err) => {
+                if !*failed {
+                    eprintln!("无法执行nvidia-smi: {}", err);
+                }
+                *failed = true;
+                return Vec::new();
+            }
+        };
+        if *failed {
+            eprintln!("nvidia-smi已恢复");
+        }
+        *failed = false;
+        String::from_utf8_lossy(&output.stdout)
+            .lines()
+            .filter_map(Nvidia::parse_line)
+            .collect()
+    }
+    //格式: 序号, 利用率, 已用显存, 总显存
+    fn parse_line(line: &str) -> Option<(u32, u8, u32, u32)> {
+        let fields: Vec<&str> = line.split(',').map(|field| field.trim()).collect();
+        if fields.len() != 4 {
+            return None;
+        }
+        Some((
+            fields[0].parse().ok()?,
+            fields[1].parse().ok()?,
+            fields[2].parse().ok()?,
+            fields[3].parse().ok()?,
+        ))
+    }
+    //当前读数是否空闲，不经过计数确认
+    pub fn is_idle(&self) -> bool {
+        (self.used_memory as f32 / self.total_memory as f32) < 0.10 && self.use_ratio < 5
     }
     pub fn is_free(&mut self) -> bool {
-        if self.used_memory as f32 / (self.total_memory as f32) < 0.10 && self.use_ratio < 5 {
+        if self.is_idle() {
             self.counter_free += 1;
             if self.counter_free > config::DEVICE_FREE {
                 self.counter_free = 0;
@@ -68,7 +143,7 @@ impl Nvidia {
     }
     pub fn is_low_efficiency(&mut self) -> bool {
         let mem_ratio = self.used_memory as f32 / self.total_memory as f32;
-        if mem_ratio >= 0.10 && mem_ratio <= 0.5 && self.use_ratio >= 5 && self.use_ratio <= 50 {
+        if (0.10..=0.5).contains(&mem_ratio) && (5..=50).contains(&self.use_ratio) {
             self.counter_efficiency += 1;
             if self.counter_efficiency > config::DEVICE_LOW_EFFICIENCY {
                 self.counter_efficiency = 0;
@@ -80,3 +155,17 @@ impl Nvidia {
         false
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_parse_line() {
+        assert_eq!(
+            Nvidia::parse_line("3, 47, 20480, 81920"),
+            Some((3, 47, 20480, 81920))
+        );
+        assert_eq!(Nvidia::parse_line("[N/A], 0, 0, 0"), None);
+        assert_eq!(Nvidia::parse_line(""), None);
+    }
+}
@@ -0,0 +1,574 @@
+use super::config;
+use super::recurrence::Recurrence;
+use super::util::Util;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::io::prelude::*;
+
+pub const VERSION: u32 = 4; //协议版本，不一致时拒绝请求
+
+//每行一个信封，version为客户端的协议版本
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Envelope {
+    pub version: u32,
+    pub request: Request,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub token: Option<String>, //远程连接的用户API令牌
+}
+impl Envelope {
+    pub fn new(request: Request) -> Envelope {
+        Envelope {
+            version: VERSION,
+            request,
+            token: None,
+        }
+    }
+    pub fn with_token(mut self, token: Option<String>) -> Envelope {
+        self.token = token;
+        self
+    }
+}
+
+//客户端请求
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Request {
+    Hello, //握手，应答中带服务端协议版本
+    Book(Booking),
+    Urgent(Booking),
+    Repeat {
+        booking: Booking,
+        rule: Recurrence,
+        until: String,
+    }, //以booking的开始时刻为首次开始时刻
+    Finish {
+        email: String,
+        id: Option<u64>,
+    },
+    Cancel {
+        email: String,
+        series: u64,
+        date: Option<String>,
+    }, //date为空时取消整个周期预约
+    Claim {
+        email: String,
+        id: Option<u64>,
+    },
+    Modify {
+        email: String,
+        id: Option<u64>,
+        duration: Option<i64>,
+        start: Option<String>,
+    }, //修改时长或开始时刻，保留原排队时间戳
+    Query {
+        email: String,
+        query: Query,
+    },
+    Watch {
+        email: Option<String>,
+    }, //订阅事件，连接保持打开；指定email时只接收其预约相关的事件
+    Admin {
+        token: Option<String>,
+        command: AdminCommand,
+    }, //管理操作，uid在白名单内或token与管理员令牌一致才执行
+}
+
+//管理操作
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum AdminCommand {
+    Stop,                                 //关闭服务
+    Approve { admin: String, id: u64 },   //批准待审批的预约，admin为署名的管理员邮箱
+    Reject { admin: String, id: u64 },    //拒绝待审批的预约
+    Release { id: u64 },                  //强制释放预约持有的显卡
+    Reorder { id: u64, position: usize }, //将排队中的预约移到指定位置
+    IssueToken { email: String },         //为用户签发远程连接的API令牌
+    RevokeToken { email: String },        //吊销用户的全部API令牌
+}
+impl Request {
+    //请求以哪个用户的身份执行，不涉及用户的请求为None
+    pub fn email(&self) -> Option<&str> {
+        match self {
+            Request::Book(booking) | Request::Urgent(booking) => Some(&booking.email),
+            Request::Repeat { booking, .. } => Some(&booking.email),
+            Request::Finish { email, .. }
+            | Request::Cancel { email, .. }
+            | Request::Claim { email, .. }
+            | Request::Modify { email, .. } => Some(email),
+            Request::Query { email, .. } if !email.is_empty() => Some(email),
+            Request::Watch { email } => email.as_deref(),
+            Request::Query { .. } | Request::Hello | Request::Admin { .. } => None,
+        }
+    }
+    //主循环假定请求内容合法，邮箱、时刻与时长在此校验
+    pub fn validate(&self) -> Result<(), Reply> {
+        if let Some(email) = self.email() {
+            check_email(email)?;
+        }
+        match self {
+            Request::Book(booking) | Request::Urgent(booking) => booking.validate(),
+            Request::Repeat { booking, until, .. } => {
+                booking.validate()?;
+                //各次都是时段预约，须有时长才能到期释放
+                if booking.duration.is_none() {
+                    return Err(bad_request("周期预约须指定时长！"));
+                }
+                check_date(until)
+            }
+            Request::Cancel {
+                date: Some(date), ..
+            } => check_date(date),
+            Request::Modify {
+                duration, start, ..
+            } => {
+                check_duration(*duration)?;
+                match start {
+                    Some(start) => check_time(start),
+                    None => Ok(()),
+                }
+            }
+            Request::Admin {
+                command:
+                    AdminCommand::IssueToken { email }
+                    | AdminCommand::RevokeToken { email }
+                    | AdminCommand::Approve { admin: email, .. }
+                    | AdminCommand::Reject { admin: email, .. },
+                ..
+            } => check_email(email),
+            _ => Ok(()),
+        }
+    }
+}
+
+//预约内容，时刻格式为"%Y-%m-%d %H:%M:%S"
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Booking {
+    pub email: String,
+    pub date_time: String, //排队时间戳由服务端按受理时刻填写，不采信客户端
+    pub duration: Option<i64>,
+    pub slot: bool,
+    pub early: bool,
+    pub deadline: Option<String>,
+}
+
+impl Booking {
+    fn validate(&self) -> Result<(), Reply> {
+        check_time(&self.date_time)?;
+        if let Some(deadline) = &self.deadline {
+            check_time(deadline)?;
+        }
+        check_duration(self.duration)
+    }
+}
+
+fn bad_request(message: &str) -> Reply {
+    Reply::error(ErrorCode::BadRequest, message.to_string())
+}
+fn check_email(email: &str) -> Result<(), Reply> {
+    Util::check_email(email)
+        .map(|_| ())
+        .map_err(|_| bad_request("邮箱格式错误！"))
+}
+//时刻格式为"%Y-%m-%d %H:%M:%S"，年份过大时后续计算会溢出
+fn check_time(time: &str) -> Result<(), Reply> {
+    match NaiveDateTime::parse_from_str(time, "%Y-%m-%d %H:%M:%S") {
+        Ok(time) if (1970..=config::MAX_YEAR).contains(&time.year()) => Ok(()),
+        _ => Err(bad_request("时刻格式错误或超出范围！")),
+    }
+}
+fn check_date(date: &str) -> Result<(), Reply> {
+    match NaiveDate::parse_from_str(date, "%Y-%m-%d") {
+        Ok(date) if (1970..=config::MAX_YEAR).contains(&date.year()) => Ok(()),
+        _ => Err(bad_request("日期格式错误或超出范围！")),
+    }
+}
+fn check_duration(duration: Option<i64>) -> Result<(), Reply> {
+    match duration {
+        Some(minutes) if !(1..=config::MAX_DURATION_MINUTES).contains(&minutes) => {
+            Err(bad_request(&format!(
+                "时长须在1到{}分钟之间！",
+                config::MAX_DURATION_MINUTES
+            )))
+        }
+        _ => Ok(()),
+    }
+}
+
+//只读查询
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Query {
+    Status,    //各显卡读数与持有者
+    Queue,     //排队列表
+    Whoami,    //email名下的预约
+    Dashboard, //状态面板所需的全部数据，含显卡读数曲线
+}
+
+//读取一行请求，最多limit字节；连接已关闭时返回None
+pub fn read_line<R: BufRead>(reader: &mut R, limit: usize) -> Result<Option<String>, Reply> {
+    let mut buffer: Vec<u8> = Vec::new();
+    let n = reader
+        .take(limit as u64 + 1)
+        .read_until(b'\n', &mut buffer)
+        .map_err(|_| bad_request("请求读取超时或连接中断！"))?;
+    if n == 0 {
+        return Ok(None);
+    }
+    if n > limit {
+        return Err(bad_request(&format!("请求超过{}字节！", limit)));
+    }
+    String::from_utf8(buffer)
+        .map(Some)
+        .map_err(|_| bad_request("请求不是UTF-8编码！"))
+}
+
+//解析一行请求，旧版本客户端的请求无法解析为信封
+pub fn parse(line: &str) -> Result<Envelope, Reply> {
+    let value: serde_json::Value =
+        serde_json::from_str(line).map_err(|_| bad_request("请求格式错误！"))?;
+    let version = value.get("version").and_then(|version| version.as_u64());
+    if version != Some(VERSION as u64) {
+        return Err(Reply::error(
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "不支持的协议版本{}，服务端版本为{}，请更新客户端！",
+                version.map_or(String::from("(无)"), |version| version.to_string()),
+                VERSION
+            ),
+        ));
+    }
+    let envelope: Envelope =
+        serde_json::from_value(value).map_err(|_| bad_request("请求格式错误！"))?;
+    envelope.request.validate()?;
+    Ok(envelope)
+}
+
+//错误码，客户端据此设置退出码
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,         //请求无法解析或参数错误
+    NotFound,           //目标预约不存在
+    Ambiguous,          //有多个预约，需要指定编号
+    Conflict,           //与其他时段预约冲突
+    Forbidden,          //无权执行
+    Rejected,           //不满足预约策略
+    Unavailable,        //服务端未运行或无响应
+    UnsupportedVersion, //协议版本不一致
+}
+impl ErrorCode {
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            ErrorCode::BadRequest => 2,
+            ErrorCode::NotFound => 3,
+            ErrorCode::Ambiguous => 4,
+            ErrorCode::Conflict => 5,
+            ErrorCode::Forbidden => 6,
+            ErrorCode::Rejected => 7,
+            ErrorCode::Unavailable => 8,
+            ErrorCode::UnsupportedVersion => 9,
+        }
+    }
+    pub fn http_status(&self) -> u16 {
+        match self {
+            ErrorCode::BadRequest | ErrorCode::UnsupportedVersion => 400,
+            ErrorCode::NotFound => 404,
+            ErrorCode::Ambiguous | ErrorCode::Conflict => 409,
+            ErrorCode::Forbidden => 403,
+            ErrorCode::Rejected => 422,
+            ErrorCode::Unavailable => 503,
+        }
+    }
+}
+
+//服务端对每条请求的应答，一行JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Reply {
+    pub ok: bool,
+    #[serde(default)]
+    pub code: Option<ErrorCode>,
+    pub message: String,
+    #[serde(default)]
+    pub id: Option<u64>, //相关的预约编号
+    #[serde(default)]
+    pub position: Option<usize>, //排队位置
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>, //查询结果，供程序读取
+}
+impl Reply {
+    pub fn ok(message: String) -> Reply {
+        Reply {
+            ok: true,
+            code: None,
+            message,
+            id: None,
+            position: None,
+            data: None,
+        }
+    }
+    pub fn error(code: ErrorCode, message: String) -> Reply {
+        Reply {
+            ok: false,
+            code: Some(code),
+            message,
+            id: None,
+            position: None,
+            data: None,
+        }
+    }
+    pub fn with_id(mut self, id: u64) -> Reply {
+        self.id = Some(id);
+        self
+    }
+    pub fn with_position(mut self, position: Option<usize>) -> Reply {
+        self.position = position;
+        self
+    }
+    pub fn with_data(mut self, data: serde_json::Value) -> Reply {
+        self.data = Some(data);
+        self
+    }
+    pub fn exit_code(&self) -> i32 {
+        match self.code {
+            Some(code) => code.exit_code(),
+            None if self.ok => 0,
+            None => 1,
+        }
+    }
+}
+impl fmt::Display for Reply {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.message)?;
+        if let Some(id) = self.id {
+            write!(f, "\n预约编号: {}", id)?;
+        }
+        if let Some(position) = self.position {
+            write!(f, "\n排队位置: {}", position)?;
+        }
+        Ok(())
+    }
+}
+
+//事件类型
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventKind {
+    Booked,      //预约成功
+    Offered,     //轮到预约，等待领取
+    Granted,     //分配或领取了显卡
+    Released,    //显卡释放
+    IdleWarning, //设备空闲通知
+    Metrics,     //定时的显卡读数
+}
+
+//订阅连接上推送的一条事件，每行一个JSON
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub time: String,
+    pub kind: EventKind,
+    pub message: String,
+    #[serde(default)]
+    pub email: Option<String>, //相关预约的用户，读数事件为空
+    #[serde(default)]
+    pub id: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<serde_json::Value>,
+}
+impl Event {
+    pub fn new(kind: EventKind, time: String, message: String) -> Event {
+        Event {
+            time,
+            kind,
+            message,
+            email: None,
+            id: None,
+            data: None,
+        }
+    }
+    pub fn with_user(mut self, email: &str, id: u64) -> Event {
+        self.email = Some(email.to_string());
+        self.id = Some(id);
+        self
+    }
+    pub fn with_data(mut self, data: serde_json::Value) -> Event {
+        self.data = Some(data);
+        self
+    }
+    //过滤条件为email时只保留该用户预约的事件
+    pub fn matches(&self, filter: Option<&str>) -> bool {
+        filter.is_none_or(|email| self.email.as_deref() == Some(email))
+    }
+}
+impl fmt::Display for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "[{}] {}", self.time, self.message)
+    }
+}
+
+#[cfg(test)]
+pub mod tests {
+    use super::*;
+    #[test]
+    fn test_reply_round_trip() {
+        let reply = Reply::ok(String::from("预约成功"))
+            .with_id(3)
+            .with_position(Some(2));
+        let line = serde_json::to_string(&reply).unwrap();
+        assert_eq!(serde_json::from_str::<Reply>(&line).unwrap(), reply);
+        assert_eq!(reply.exit_code(), 0);
+        let error = Reply::error(ErrorCode::Conflict, String::from("冲突"));
+        assert_eq!(error.exit_code(), 5);
+    }
+    #[test]
+    fn test_parse_version() {
+        let line = serde_json::to_string(&Envelope::new(Request::Hello)).unwrap();
+        assert_eq!(parse(&line).unwrap().request, Request::Hello);
+        //旧版本客户端直接发送预约内容
+        let old =
+            r#"{"urg":false,"finish":true,"timestamp":0,"email":"stop@stop.stop","date_time":""}"#;
+        assert_eq!(
+            parse(old).unwrap_err().code,
+            Some(ErrorCode::UnsupportedVersion)
+        );
+        let newer = r#"{"version":99,"request":"Hello"}"#;
+        assert_eq!(
+            parse(newer).unwrap_err().code,
+            Some(ErrorCode::UnsupportedVersion)
+        );
+        assert_eq!(
+            parse("not json").unwrap_err().code,
+            Some(ErrorCode::BadRequest)
+        );
+    }
+    fn booking(date_time: &str, duration: Option<i64>) -> Request {
+        Request::Book(Booking {
+            email: String::from("a@a.com"),
+            date_time: date_time.to_string(),
+            duration,
+            slot: false,
+            early: false,
+            deadline: None,
+        })
+    }
+    #[test]
+    fn test_validate() {
+        assert!(booking("2030-01-01 08:00:00", Some(60)).validate().is_ok());
+        for request in [
+            booking("2030-02-30 08:00:00", None),
+            booking("tomorrow", None),
+            booking("99999-01-01 08:00:00", None),
+            booking("2030-01-01 08:00:00", Some(0)),
+            booking("2030-01-01 08:00:00", Some(i64::MAX)),
+            Request::Finish {
+                email: String::from("not an email"),
+                id: None,
+            },
+            Request::Cancel {
+                email: String::from("a@a.com"),
+                series: 1,
+                date: Some(String::from("2030-13-01")),
+            },
+            //没有时长的周期预约无法到期释放
+            Request::Repeat {
+                booking: Booking {
+                    email: String::from("a@a.com"),
+                    date_time: String::from("2030-01-01 08:00:00"),
+                    duration: None,
+                    slot: true,
+                    early: false,
+                    deadline: None,
+                },
+                rule: Recurrence::Daily,
+                until: String::from("2030-12-31"),
+            },
+        ] {
+            let line = serde_json::to_string(&Envelope::new(request)).unwrap();
+            assert_eq!(parse(&line).unwrap_err().code, Some(ErrorCode::BadRequest));
+        }
+    }
+    #[test]
+    fn test_read_line() {
+        let mut input: &[u8] = b"{\"a\":1}\nrest";
+        assert_eq!(read_line(&mut input, 64).unwrap().unwrap(), "{\"a\":1}\n");
+        assert_eq!(read_line(&mut input, 64).unwrap().unwrap(), "rest");
+        assert_eq!(read_line(&mut input, 64).unwrap(), None);
+        let long = vec![b'x'; 100];
+        assert!(read_line(&mut long.as_slice(), 64).is_err());
+        let mut invalid: &[u8] = b"\xff\xfe\n";
+        assert!(read_line(&mut invalid, 64).is_err());
+    }
+
+    //确定性的伪随机序列(xorshift64)，失败时可按轮次复现
+    pub fn xorshift(state: &mut u64) -> u64 {
+        *state ^= *state << 13;
+        *state ^= *state >> 7;
+        *state ^= *state << 17;
+        *state
+    }
+    //对合法请求逐字节变异，解析只能返回应答或合法的请求
+    #[test]
+    fn test_fuzz_parse() {
+        let seeds: Vec<String> = [
+            booking("2030-01-01 08:00:00", Some(60)),
+            Request::Modify {
+                email: String::from("a@a.com"),
+                id: Some(1),
+                duration: Some(30),
+                start: Some(String::from("2030-01-01 09:00:00")),
+            },
+            Request::Repeat {
+                booking: Booking {
+                    email: String::from("a@a.com"),
+                    date_time: String::from("2030-01-01 08:00:00"),
+                    duration: Some(60),
+                    slot: true,
+                    early: false,
+                    deadline: Some(String::from("2030-01-01 12:00:00")),
+                },
+                rule: Recurrence::Weekdays(vec![0, 2]),
+                until: String::from("2030-02-01"),
+            },
+            Request::Admin {
+                token: None,
+                command: AdminCommand::Reorder { id: 1, position: 2 },
+            },
+        ]
+        .into_iter()
+        .map(|request| serde_json::to_string(&Envelope::new(request)).unwrap())
+        .collect();
+        let alphabet = b"{}[]\":,0123456789-.eE+ tfnulx\\\xff\n";
+        let mut state = 0x2545_f491_4f6c_dd1d;
+        let (mut accepted, mut rejected) = (0, 0);
+        for round in 0..20000 {
+            let mut bytes = seeds[round % seeds.len()].clone().into_bytes();
+            for _ in 0..1 + xorshift(&mut state) % 4 {
+                let at = xorshift(&mut state) as usize % (bytes.len() + 1);
+                let byte = alphabet[xorshift(&mut state) as usize % alphabet.len()];
+                match xorshift(&mut state) % 3 {
+                    0 if at < bytes.len() => bytes[at] = byte,
+                    1 if at < bytes.len() => {
+                        bytes.remove(at);
+                    }
+                    _ => bytes.insert(at, byte),
+                }
+            }
+            let line = match read_line(&mut bytes.as_slice(), 1024) {
+                Ok(Some(line)) => line,
+                _ => continue,
+            };
+            match parse(&line) {
+                //接受的信封序列化后再次解析，结果不变
+                Ok(envelope) => {
+                    accepted += 1;
+                    let again = parse(&serde_json::to_string(&envelope).unwrap());
+                    assert_eq!(again, Ok(envelope), "round {}", round);
+                }
+                //拒绝时都带错误码，客户端据此返回退出码
+                Err(reply) => {
+                    rejected += 1;
+                    assert!(!reply.ok && reply.code.is_some(), "round {}", round);
+                }
+            }
+        }
+        //变异后的请求既有被接受的也有被拒绝的，两条路径都经过检查
+        assert!(accepted > 0 && rejected > 0);
+    }
+}
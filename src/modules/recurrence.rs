@@ -0,0 +1,90 @@
+use super::config;
+use chrono::prelude::*;
+use serde::{Deserialize, Serialize};
+
+//周期规则，星期以周一为0
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub enum Recurrence {
+    Daily,
+    Weekly,
+    Weekdays(Vec<u32>),
+}
+
+impl Recurrence {
+    //Eg: daily / weekly / mon,wed,fri
+    pub fn parse(rule: &str) -> Option<Recurrence> {
+        match rule {
+            "daily" => Some(Recurrence::Daily),
+            "weekly" => Some(Recurrence::Weekly),
+            _ => {
+                let mut days: Vec<u32> = Vec::new();
+                for day in rule.split(',') {
+                    let day = day.trim().parse::<Weekday>().ok()?;
+                    days.push(day.num_days_from_monday());
+                }
+                days.sort();
+                days.dedup();
+                Some(Recurrence::Weekdays(days))
+            }
+        }
+    }
+    fn matches(&self, first: NaiveDate, date: NaiveDate) -> bool {
+        match self {
+            Recurrence::Daily => true,
+            Recurrence::Weekly => date.weekday() == first.weekday(),
+            Recurrence::Weekdays(days) => days.contains(&date.weekday().num_days_from_monday()),
+        }
+    }
+    //从首次开始时刻展开到截止日期(含)，数量不超过MAX_OCCURRENCES
+    pub fn expand(&self, first: NaiveDateTime, until: NaiveDate) -> Vec<NaiveDateTime> {
+        first
+            .date()
+            .iter_days()
+            .take_while(|date| *date <= until)
+            .filter(|date| self.matches(first.date(), *date))
+            .take(config::MAX_OCCURRENCES)
+            .map(|date| NaiveDateTime::new(date, first.time()))
+            .collect()
+    }
+}
+
+impl std::fmt::Display for Recurrence {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Recurrence::Daily => write!(f, "每天"),
+            Recurrence::Weekly => write!(f, "每周"),
+            Recurrence::Weekdays(days) => {
+                let names = ["一", "二", "三", "四", "五", "六", "日"];
+                let days: Vec<&str> = days.iter().map(|day| names[*day as usize % 7]).collect();
+                write!(f, "每周{}", days.join("、"))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    #[test]
+    fn test_expand_weekdays() {
+        let rule = Recurrence::parse("tue,thu").unwrap();
+        let first =
+            NaiveDateTime::parse_from_str("2030-01-01 14:00:00", "%Y-%m-%d %H:%M:%S").unwrap();
+        let until = NaiveDate::from_ymd_opt(2030, 1, 10).unwrap();
+        let dates: Vec<String> = rule
+            .expand(first, until)
+            .iter()
+            .map(|x| x.to_string())
+            .collect();
+        assert_eq!(
+            dates,
+            vec![
+                "2030-01-01 14:00:00",
+                "2030-01-03 14:00:00",
+                "2030-01-08 14:00:00",
+                "2030-01-10 14:00:00"
+            ]
+        );
+        assert!(Recurrence::parse("someday").is_none());
+    }
+}
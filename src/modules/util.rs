@@ -1,5 +1,16 @@
+use super::config;
 use chrono::prelude::*;
+use lazy_static::lazy_static;
 use regex::Regex;
+use sha2::{Digest, Sha256};
+use std::io::Read;
+
+lazy_static! {
+    //服务端逐条校验请求中的邮箱，正则只编译一次
+    static ref EMAIL: Regex =
+        Regex::new(r"^[A-Za-z\d]+([-_.][A-Za-z\d]+)*@([A-Za-z\d]+[-.])+[A-Za-z\d]{2,4}$")
+            .unwrap();
+}
 
 #[derive(Debug)]
 pub enum UtilError {
@@ -11,9 +22,7 @@ pub enum UtilError {
 pub struct Util;
 impl Util {
     pub fn check_email(email: &str) -> Result<bool, UtilError> {
-        let re = Regex::new(r"^[A-Za-z\d]+([-_.][A-Za-z\d]+)*@([A-Za-z\d]+[-.])+[A-Za-z\d]{2,4}$")
-            .unwrap();
-        if re.is_match(email) {
+        if EMAIL.is_match(email) {
             return Ok(true);
         }
         Err(UtilError::FormatError)
@@ -21,30 +30,45 @@ impl Util {
 
     pub fn check_date(date: &str) -> Result<NaiveDate, UtilError> {
         let now: NaiveDateTime = NaiveDateTimeWrapper::from(Local::now()).into();
-        let dst = NaiveDate::parse_from_str(date, "%Y-%m-%d");
-        if dst.unwrap() < now.date() {
+        let dst = NaiveDate::parse_from_str(date, "%Y-%m-%d").map_err(|_| UtilError::ParseError)?;
+        if dst < now.date() {
             return Err(UtilError::TimeLateErr);
         }
-        if dst.is_ok() {
-            Ok(dst.unwrap())
-        } else {
-            Err(UtilError::ParseError)
-        }
+        Ok(dst)
     }
     pub fn check_date_time(date: &str, time: &str) -> Result<NaiveDateTime, UtilError> {
-        let now = NaiveDateTimeWrapper::from(Local::now()).into();
+        let now: NaiveDateTime = NaiveDateTimeWrapper::from(Local::now()).into();
         let dst = NaiveDateTime::parse_from_str(
             format!("{} {}", date, time).as_str(),
             "%Y-%m-%d %H:%M:%S",
-        );
-        if dst.unwrap() < now {
+        )
+        .map_err(|_| UtilError::ParseError)?;
+        if dst < now {
             return Err(UtilError::TimeLateErr);
         }
-        if dst.is_ok() {
-            Ok(dst.unwrap())
-        } else {
-            Err(UtilError::ParseError)
+        Ok(dst)
+    }
+    //从/dev/urandom读取随机字节，以十六进制返回
+    pub fn random_token(bytes: usize) -> std::io::Result<String> {
+        let mut buffer = vec![0u8; bytes];
+        std::fs::File::open("/dev/urandom")?.read_exact(&mut buffer)?;
+        Ok(buffer.iter().map(|byte| format!("{:02x}", byte)).collect())
+    }
+    //API令牌只保存SHA-256摘要，以十六进制返回
+    pub fn hash_token(token: &str) -> String {
+        Sha256::digest(token.as_bytes())
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect()
+    }
+    //时长以小时输入，允许小数，返回分钟数，不超过MAX_DURATION_MINUTES
+    pub fn check_hours(hours: &str) -> Result<i64, UtilError> {
+        let hours: f64 = hours.parse().map_err(|_| UtilError::ParseError)?;
+        let minutes = (hours * 60.0).round();
+        if !minutes.is_finite() || minutes <= 0.0 || minutes > config::MAX_DURATION_MINUTES as f64 {
+            return Err(UtilError::ParseError);
         }
+        Ok(minutes as i64)
     }
 }
 
@@ -62,9 +86,9 @@ impl From<DateTime<Local>> for NaiveDateTimeWrapper {
         }
     }
 }
-impl Into<NaiveDateTime> for NaiveDateTimeWrapper {
-    fn into(self) -> NaiveDateTime {
-        self.naive_dt
+impl From<NaiveDateTimeWrapper> for NaiveDateTime {
+    fn from(wrapper: NaiveDateTimeWrapper) -> NaiveDateTime {
+        wrapper.naive_dt
     }
 }
 
@@ -83,4 +107,26 @@ mod tests {
         assert_eq!(n_dt_w.date(), n_dt.date());
         assert_eq!(n_dt_w.time(), n_dt.time());
     }
+    #[test]
+    fn test_random_token() {
+        let token = Util::random_token(16).unwrap();
+        assert_eq!(token.len(), 32);
+        assert!(token.chars().all(|c| c.is_ascii_hexdigit()));
+        assert_ne!(token, Util::random_token(16).unwrap());
+    }
+    #[test]
+    fn test_hash_token() {
+        assert_eq!(
+            Util::hash_token("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+    #[test]
+    fn test_check_hours() {
+        assert_eq!(Util::check_hours("6").unwrap(), 360);
+        assert_eq!(Util::check_hours("1.5").unwrap(), 90);
+        assert!(Util::check_hours("0").is_err());
+        assert!(Util::check_hours("abc").is_err());
+        assert!(Util::check_hours("1e18").is_err());
+    }
 }